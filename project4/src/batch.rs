@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+
+// a group of writes applied atomically by KvsEngine::write_batch
+// removing a key which does not exist is not an error within a batch
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set(
+        #[serde(with = "crate::binary")] Vec<u8>,
+        #[serde(with = "crate::binary")] Vec<u8>,
+    ),
+    Rm(#[serde(with = "crate::binary")] Vec<u8>),
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key.into(), value.into()));
+        self
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Rm(key.into()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
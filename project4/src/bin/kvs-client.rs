@@ -1,12 +1,16 @@
+use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
 use tracing::info;
-use tracing_subscriber;
 
-use kvs::{KvsClient, KvsError, Result};
+use kvs::{prefix_range_bytes, KvsClient, KvsError, Result};
 
-const DEFAULT_SERVER_ADDR: &'static str = "127.0.0.1:4000";
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:4000";
+const SCAN_PAGE_SIZE: usize = 256;
 
 // clap(version) adds -V option
 #[derive(Parser, Debug)]
@@ -21,6 +25,9 @@ enum SC {
     Set {
         key: String,
         value: String,
+        // seconds after which the key expires
+        #[clap(long)]
+        ttl: Option<u64>,
         #[clap(short, long)]
         addr: Option<String>,
     },
@@ -34,6 +41,48 @@ enum SC {
         #[clap(short, long)]
         addr: Option<String>,
     },
+    // list the keys from start (inclusive) to end (exclusive), or those with a prefix
+    Scan {
+        #[clap(long)]
+        start: Option<String>,
+        #[clap(long)]
+        end: Option<String>,
+        #[clap(long, conflicts_with_all = &["start", "end"])]
+        prefix: Option<String>,
+        #[clap(long)]
+        limit: Option<usize>,
+        #[clap(short, long)]
+        addr: Option<String>,
+    },
+    // print the statistics of the engine
+    Stats {
+        #[clap(short, long)]
+        addr: Option<String>,
+    },
+    // take a backup of the server into a local dir, which must not hold a store yet
+    Backup {
+        dir: PathBuf,
+        #[clap(short, long)]
+        addr: Option<String>,
+    },
+    // replace everything the server holds with a backup in a local dir
+    Restore {
+        dir: PathBuf,
+        #[clap(short, long)]
+        addr: Option<String>,
+    },
+    // print the sets and removes of a key, or of the keys with a prefix, as they happen
+    Watch {
+        key: String,
+        // watch the keys starting with key
+        #[clap(long)]
+        prefix: bool,
+        // resume after the change with this sequence number
+        #[clap(long)]
+        since: Option<u64>,
+        #[clap(short, long)]
+        addr: Option<String>,
+    },
 }
 
 fn main() -> Result<()> {
@@ -44,13 +93,21 @@ fn main() -> Result<()> {
     let opts = Opts::parse();
 
     match opts.subcmd {
-        SC::Set { key, value, addr } => {
+        SC::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
             let addr = addr_str
                 .parse::<SocketAddr>()
                 .map_err(|_| KvsError::InvalidAddr(addr_str))?;
             let mut client = KvsClient::new(addr)?;
-            client.set(key, value)?
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl))?,
+                None => client.set(key, value)?,
+            }
         }
         SC::Get { key, addr } => {
             let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
@@ -59,8 +116,11 @@ fn main() -> Result<()> {
                 .map_err(|_| KvsError::InvalidAddr(addr_str))?;
             let mut client = KvsClient::new(addr)?;
             if let Some(v) = client.get(key)? {
-                info!(value = v.as_str(), "the value of key is");
-                println!("{}", v);
+                info!(
+                    value = String::from_utf8_lossy(&v).as_ref(),
+                    "the value of key is"
+                );
+                print_line(&[&v])?;
             } else {
                 info!("no such key");
                 println!("Key not found");
@@ -74,6 +134,103 @@ fn main() -> Result<()> {
             let mut client = KvsClient::new(addr)?;
             client.remove(key)?
         }
+        SC::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        } => {
+            let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
+            let addr = addr_str
+                .parse::<SocketAddr>()
+                .map_err(|_| KvsError::InvalidAddr(addr_str))?;
+            let mut client = KvsClient::new(addr)?;
+            let (mut start, end) = match prefix {
+                Some(prefix) => prefix_range_bytes(prefix.as_bytes()),
+                None => (
+                    start.map_or(Bound::Unbounded, |s| Bound::Included(s.into_bytes())),
+                    end.map_or(Bound::Unbounded, |e| Bound::Excluded(e.into_bytes())),
+                ),
+            };
+            let mut remaining = limit.unwrap_or(usize::MAX);
+            while remaining > 0 {
+                let (entries, next) =
+                    client.scan(start, end.clone(), remaining.min(SCAN_PAGE_SIZE))?;
+                remaining -= entries.len();
+                for (key, value) in entries {
+                    print_line(&[&key, &value])?;
+                }
+                match next {
+                    Some(key) => start = Bound::Excluded(key),
+                    None => break,
+                }
+            }
+        }
+        SC::Stats { addr } => {
+            let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
+            let addr = addr_str
+                .parse::<SocketAddr>()
+                .map_err(|_| KvsError::InvalidAddr(addr_str))?;
+            let mut client = KvsClient::new(addr)?;
+            let stats = client.stats()?;
+            println!("live_keys {}", stats.live_keys);
+            println!("disk_bytes {}", stats.disk_bytes);
+            println!("stale_bytes {}", stats.stale_bytes);
+            println!("segments {}", stats.segments);
+            println!("compactions {}", stats.compactions);
+            match stats.last_compaction_ms {
+                Some(ms) => println!("last_compaction_ms {}", ms),
+                None => println!("last_compaction_ms -"),
+            }
+            println!("index_bytes {}", stats.index_bytes);
+            println!("compression_ratio {:.2}", stats.compression_ratio);
+        }
+        SC::Backup { dir, addr } => {
+            let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
+            let addr = addr_str
+                .parse::<SocketAddr>()
+                .map_err(|_| KvsError::InvalidAddr(addr_str))?;
+            let mut client = KvsClient::new(addr)?;
+            client.backup(dir)?
+        }
+        SC::Restore { dir, addr } => {
+            let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
+            let addr = addr_str
+                .parse::<SocketAddr>()
+                .map_err(|_| KvsError::InvalidAddr(addr_str))?;
+            let mut client = KvsClient::new(addr)?;
+            client.restore(dir)?
+        }
+        SC::Watch {
+            key,
+            prefix,
+            since,
+            addr,
+        } => {
+            let addr_str = addr.unwrap_or(DEFAULT_SERVER_ADDR.to_owned());
+            let addr = addr_str
+                .parse::<SocketAddr>()
+                .map_err(|_| KvsError::InvalidAddr(addr_str))?;
+            let client = KvsClient::new(addr)?;
+            // a line per change: its sequence number, then set with the key and value or rm with the key
+            for change in client.watch(key, prefix, since)? {
+                let change = change?;
+                let seq = change.seq.to_string();
+                match change.value {
+                    Some(value) => print_line(&[seq.as_bytes(), b"set", &change.key, &value])?,
+                    None => print_line(&[seq.as_bytes(), b"rm", &change.key])?,
+                }
+            }
+        }
     };
     Ok(())
 }
+
+// keys and values are printed as they are, whether or not they are utf-8
+fn print_line(fields: &[&[u8]]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&fields.join(&b' '))?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
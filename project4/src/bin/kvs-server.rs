@@ -1,4 +1,8 @@
 // kvs-server [--addr IP-PORT(string)] [--engine ENGINE-NAME(string)]
+//            [--durability DURABILITY(string)] [--sync-interval-ms MILLIS(u64)]
+//            [--compression CODEC(string)] [--segment-size BYTES(u64)]
+//            [--compaction POLICY(string)] [--write-buffer-size BYTES(usize)]
+//            [--cache-size BYTES(usize)] [--read-only] [--refresh-interval-ms MILLIS(u64)]
 // kvs-server -V
 
 use std::env::current_dir;
@@ -6,18 +10,22 @@ use std::fs::{self, create_dir_all};
 use std::net::{AddrParseError, SocketAddr};
 use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 
 use clap::Parser;
 use tracing::{error, info};
-use tracing_subscriber;
 
 use kvs::{
-    KvStore, KvsEngine, KvsError, KvsServer, NaiveThreadPool, RayonThreadPool, Result,
-    SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+    CompactionPolicy, Compression, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    KvsServer, RayonThreadPool, Result, SledKvsEngine, ThreadPool,
 };
 
-const DEFAULT_ENGINE: &'static str = "kvs";
-const DEFAULT_ADDR: &'static str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_DURABILITY: &str = "none";
+const DEFAULT_SYNC_INTERVAL_MS: u64 = 100;
+const DEFAULT_REFRESH_INTERVAL_MS: u64 = 1000;
+const DEFAULT_COMPRESSION: &str = "none";
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -27,6 +35,33 @@ struct Args {
 
     #[clap(short, long)]
     engine: Option<String>, // either kvs or sled
+
+    #[clap(long)]
+    durability: Option<String>, // sync, group, periodic or none, kvs engine only
+
+    #[clap(long)]
+    sync_interval_ms: Option<u64>, // how often the periodic durability syncs
+
+    #[clap(long)]
+    compression: Option<String>, // lz4, snappy or none, kvs engine only
+
+    #[clap(long)]
+    segment_size: Option<u64>, // the size logs are sealed at, kvs engine only
+
+    #[clap(long)]
+    compaction: Option<String>, // off, the number of sealed logs to compact at or garbage:RATIO, kvs engine only
+
+    #[clap(long)]
+    write_buffer_size: Option<usize>, // kvs engine only
+
+    #[clap(long)]
+    cache_size: Option<usize>, // the bytes of values cached, none by default, kvs engine only
+
+    #[clap(long)]
+    read_only: bool, // serve reads only, kvs engine only
+
+    #[clap(long)]
+    refresh_interval_ms: Option<u64>, // how often a read-only server catches up with the writer
 }
 
 fn main() -> Result<()> {
@@ -44,6 +79,32 @@ fn main() -> Result<()> {
     let addr = parse_addr(&addr_str).map_err(|_| KvsError::InvalidAddr(addr_str.to_owned()))?;
 
     let engine = args.engine.unwrap_or(DEFAULT_ENGINE.to_owned());
+    let durability = parse_durability(
+        &args.durability.unwrap_or(DEFAULT_DURABILITY.to_owned()),
+        args.sync_interval_ms.unwrap_or(DEFAULT_SYNC_INTERVAL_MS),
+    )?;
+    let compression =
+        parse_compression(&args.compression.unwrap_or(DEFAULT_COMPRESSION.to_owned()))?;
+    let mut options = KvStoreOptions::new()
+        .durability(durability)
+        .compression(compression)
+        .read_only(args.read_only)
+        .refresh_interval(Duration::from_millis(
+            args.refresh_interval_ms
+                .unwrap_or(DEFAULT_REFRESH_INTERVAL_MS),
+        ));
+    if let Some(segment_size) = args.segment_size {
+        options = options.segment_size(segment_size);
+    }
+    if let Some(compaction) = args.compaction {
+        options = options.compaction_policy(parse_compaction(&compaction)?);
+    }
+    if let Some(write_buffer_size) = args.write_buffer_size {
+        options = options.write_buffer_size(write_buffer_size);
+    }
+    if let Some(cache_size) = args.cache_size {
+        options = options.cache_capacity(cache_size);
+    }
     if let Some(existing_engine) = current_engine()? {
         if existing_engine != engine {
             error!("inconsistent kv engine");
@@ -59,9 +120,9 @@ fn main() -> Result<()> {
     match engine.as_ref() {
         "kvs" => {
             // info!("server runs with engine: kvs and addr: {}", &addr);
+            // the store creates its data dir itself
             let dir = Path::new("./fuck");
-            create_dir_all(dir)?;
-            let engine = KvStore::open(dir)?;
+            let engine = KvStore::open_with(dir, options)?;
             run_with_engine(addr, engine)
         }
         "sled" => {
@@ -81,6 +142,45 @@ fn parse_addr(addr: &str) -> std::result::Result<SocketAddr, AddrParseError> {
     addr.parse::<SocketAddr>()
 }
 
+fn parse_durability(durability: &str, sync_interval_ms: u64) -> Result<Durability> {
+    match durability {
+        "sync" => Ok(Durability::Sync),
+        "group" => Ok(Durability::GroupCommit),
+        "periodic" => Ok(Durability::Periodic(Duration::from_millis(
+            sync_interval_ms,
+        ))),
+        "none" => Ok(Durability::None),
+        _ => Err(KvsError::InvalidDurability(durability.to_owned())),
+    }
+}
+
+fn parse_compression(compression: &str) -> Result<Compression> {
+    match compression {
+        "lz4" => Ok(Compression::Lz4),
+        "snappy" => Ok(Compression::Snappy),
+        "none" => Ok(Compression::None),
+        _ => Err(KvsError::InvalidCompression(compression.to_owned())),
+    }
+}
+
+fn parse_compaction(compaction: &str) -> Result<CompactionPolicy> {
+    match compaction {
+        "off" => Ok(CompactionPolicy::Disabled),
+        policy => match policy.strip_prefix("garbage:") {
+            Some(ratio) => match ratio.parse::<f64>() {
+                Ok(ratio) if (0.0..=1.0).contains(&ratio) => {
+                    Ok(CompactionPolicy::GarbageRatio(ratio))
+                }
+                _ => Err(KvsError::InvalidCompactionPolicy(compaction.to_owned())),
+            },
+            None => match policy.parse::<usize>() {
+                Ok(logs) => Ok(CompactionPolicy::SealedLogs(logs)),
+                Err(_) => Err(KvsError::InvalidCompactionPolicy(compaction.to_owned())),
+            },
+        },
+    }
+}
+
 fn run_with_engine<E: KvsEngine + 'static>(addr: SocketAddr, engine: E) -> Result<()> {
     let mut server = KvsServer::new(addr, engine, RayonThreadPool::new(10)?);
     server.run()
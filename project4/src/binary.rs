@@ -0,0 +1,176 @@
+// serde helpers for the byte strings of keys and values, used with #[serde(with = ...)]
+//
+// serde encodes a Vec<u8> as a sequence of integers,
+// these helpers encode it as bytes instead, which bson stores as binary as is;
+// strings decode as well, so logs written when keys were strings stay readable
+
+use std::fmt;
+use std::ops::Bound;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+pub(crate) fn serialize<S: Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_byte_buf(BytesVisitor)
+}
+
+// decode the bytes of a value for the String API
+pub(crate) fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
+
+pub(crate) fn into_string_opt(bytes: Option<Vec<u8>>) -> Result<Option<String>> {
+    bytes.map(into_string).transpose()
+}
+
+pub(crate) fn into_string_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((into_string(key)?, into_string(value)?)))
+        .collect()
+}
+
+pub(crate) fn bound_bytes(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(s) => Bound::Included(s.clone().into_bytes()),
+        Bound::Excluded(s) => Bound::Excluded(s.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor).map(ByteBuf)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> std::result::Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Vec<u8>, E> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<Vec<u8>, E> {
+        Ok(v.into_bytes())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+pub(crate) mod option {
+    use super::{ByteBuf, Bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(Bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|b| b.0))
+    }
+}
+
+pub(crate) mod bound {
+    use std::ops::Bound;
+
+    use super::{ByteBuf, Bytes};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bound: &Bound<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bound {
+            Bound::Included(bytes) => Bound::Included(Bytes(bytes)),
+            Bound::Excluded(bytes) => Bound::Excluded(Bytes(bytes)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+        .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Bound<Vec<u8>>, D::Error> {
+        Ok(match Bound::<ByteBuf>::deserialize(deserializer)? {
+            Bound::Included(b) => Bound::Included(b.0),
+            Bound::Excluded(b) => Bound::Excluded(b.0),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    }
+}
+
+pub(crate) mod pairs {
+    use super::{ByteBuf, Bytes};
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(pairs.len()))?;
+        for (key, value) in pairs {
+            seq.serialize_element(&(Bytes(key), Bytes(value)))?;
+        }
+        seq.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Pairs, D::Error> {
+        Ok(Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key.0, value.0))
+            .collect())
+    }
+}
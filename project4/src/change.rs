@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+// a set or a removal of a key, as streamed by KvsEngine::changes_since
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    // the sequence number of the write, which the keys of a batch share
+    pub seq: u64,
+    #[serde(with = "crate::binary")]
+    pub key: Vec<u8>,
+    // the value set, None for a removal
+    #[serde(with = "crate::binary::option")]
+    pub value: Option<Vec<u8>>,
+}
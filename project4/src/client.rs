@@ -1,18 +1,26 @@
 use std::{
-    io::{BufReader, Write},
+    fs,
+    io::{BufReader, ErrorKind, Write},
     net::{SocketAddr, TcpStream},
+    ops::Bound,
+    path::Path,
+    time::Duration,
 };
 
-use crate::{transmit::to_bytes, KvsError, Response, Result, KSP};
+use crate::{
+    transmit::{read_message, send_files, to_bytes, FileReceiver},
+    Change, EngineStats, KvsError, Response, Result, WriteBatch, KSP,
+};
 
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
 use tracing::info;
 
+// a page of scanned entries and the key to continue after, if any
+type Page = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
+// keys and values are byte strings, a String converts into one as is
 pub struct KvsClient {
-    addr: SocketAddr,
     stream: TcpStream,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
 }
 
 impl KvsClient {
@@ -20,16 +28,13 @@ impl KvsClient {
         info!(addr = format!("{:?}", &addr).as_str(), "connecting to");
         let stream = TcpStream::connect(addr)
             .map_err(|_| KvsError::ServerConnFail(format!("{:?}", addr)))?;
-        let reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
-        Ok(Self {
-            addr,
-            stream,
-            reader,
-        })
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self { stream, reader })
     }
 
-    pub fn set(&mut self, key: String, val: String) -> Result<()> {
-        info!(key = key.as_str(), val = val.as_str(), "client set");
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, val) = (key.into(), val.into());
+        info!(key = lossy(&key).as_ref(), "client set");
         self.send_request(KSP::Set(key, val))?;
         info!("client waiting for set resp");
         match self.get_response()? {
@@ -39,8 +44,27 @@ impl KvsClient {
         }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        info!(key = key.as_str(), "client get");
+    // set a key which expires once ttl has passed
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, val) = (key.into(), val.into());
+        info!(key = lossy(&key).as_ref(), "client set with ttl");
+        self.send_request(KSP::SetWithTtl(key, val, ttl.as_millis() as u64))?;
+        info!("client waiting for set resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), "client get");
         self.send_request(KSP::Get(key))?;
         info!("client waiting for get resp");
         match self.get_response()? {
@@ -50,8 +74,9 @@ impl KvsClient {
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        info!(key = key.as_str(), "client remove");
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), "client remove");
         self.send_request(KSP::Rm(key))?;
         info!("client waiting for resp");
         match self.get_response()? {
@@ -61,6 +86,300 @@ impl KvsClient {
         }
     }
 
+    // swap the value of key from expected to new,
+    // returns the current value instead if it is not the expected one
+    pub fn compare_and_swap(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), "client compare and swap");
+        self.send_request(KSP::Cas(key, expected, new))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(Ok(())),
+            Response::Mismatch(current) => Ok(Err(current)),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // set key unless it exists, returns the existing value if it does
+    pub fn set_if_absent(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let (key, val) = (key.into(), val.into());
+        info!(key = lossy(&key).as_ref(), "client set if absent");
+        self.send_request(KSP::SetIfAbsent(key, val))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(None),
+            Response::Mismatch(current) => Ok(current),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // set key and return its previous value
+    pub fn get_set(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        val: impl Into<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let (key, val) = (key.into(), val.into());
+        info!(key = lossy(&key).as_ref(), "client get set");
+        self.send_request(KSP::GetSet(key, val))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::OkWith(s) => Ok(s),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        info!(ops = batch.len(), "client write batch");
+        self.send_request(KSP::Batch(batch))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // scan one page of the keys within (start, end)
+    // and return it with the key to continue after, if the scan goes on
+    pub fn scan(
+        &mut self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Page> {
+        info!(limit, "client scan");
+        self.send_request(KSP::Scan(start, end, limit))?;
+        info!("client waiting for scan resp");
+        match self.get_response()? {
+            Response::Scan(entries, next) => Ok((entries, next)),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // take a snapshot on the server, which lives until released or until the client is dropped,
+    // returns its id for the reads against it
+    pub fn snapshot(&mut self) -> Result<u64> {
+        info!("client snapshot");
+        self.send_request(KSP::Snapshot(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Snapshot(id) => Ok(id),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn snapshot_get(
+        &mut self,
+        snapshot: u64,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        info!(snapshot, key = lossy(&key).as_ref(), "client snapshot get");
+        self.send_request(KSP::SnapshotGet(snapshot, key))?;
+        info!("client waiting for get resp");
+        match self.get_response()? {
+            Response::OkWith(s) => Ok(s),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // scan one page of a snapshot, as scan does
+    pub fn snapshot_scan(
+        &mut self,
+        snapshot: u64,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Page> {
+        info!(snapshot, limit, "client snapshot scan");
+        self.send_request(KSP::SnapshotScan(snapshot, start, end, limit))?;
+        info!("client waiting for scan resp");
+        match self.get_response()? {
+            Response::Scan(entries, next) => Ok((entries, next)),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn release_snapshot(&mut self, snapshot: u64) -> Result<()> {
+        info!(snapshot, "client release snapshot");
+        self.send_request(KSP::ReleaseSnapshot(snapshot))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // begin a transaction on the server, which lives until committed, rolled back
+    // or until the client is dropped; a client runs one transaction at a time
+    pub fn begin(&mut self) -> Result<()> {
+        info!("client begin");
+        self.send_request(KSP::Begin(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // a conflicting read ends the transaction with KvsError::Conflict
+    pub fn txn_get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), "client txn get");
+        self.send_request(KSP::TxnGet(key))?;
+        info!("client waiting for get resp");
+        match self.get_response()? {
+            Response::OkWith(s) => Ok(s),
+            Response::Conflict(()) => Err(KvsError::Conflict),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn txn_set(&mut self, key: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, val) = (key.into(), val.into());
+        info!(key = lossy(&key).as_ref(), "client txn set");
+        self.send_request(KSP::TxnSet(key, val))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn txn_remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), "client txn remove");
+        self.send_request(KSP::TxnRm(key))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // fails with KvsError::Conflict if a key the transaction read has changed since
+    pub fn commit(&mut self) -> Result<()> {
+        info!("client commit");
+        self.send_request(KSP::Commit(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Conflict(()) => Err(KvsError::Conflict),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        info!("client rollback");
+        self.send_request(KSP::Rollback(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        info!("client stats");
+        self.send_request(KSP::Stats(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Stats(stats) => Ok(stats),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // take a backup on the server and write it into dir, which must not hold a store yet;
+    // the backup opens as a store of its own or restores with restore
+    pub fn backup(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        info!(dir = dir.to_string_lossy().as_ref(), "client backup");
+        if dir.is_dir() && fs::read_dir(dir)?.next().is_some() {
+            return Err(KvsError::StoreExists(dir.display().to_string()));
+        }
+        fs::create_dir_all(dir)?;
+        self.send_request(KSP::Backup(()))?;
+        info!("client waiting for backup files");
+        let mut files = FileReceiver::new(dir);
+        loop {
+            match self.get_response()? {
+                Response::BackupFile(name, chunk) => files.receive(name, &chunk)?,
+                Response::Ok(()) => return files.finish(),
+                Response::Err(s) => return Err(KvsError::RequestError(s)),
+                _ => panic!("unexpected resp type"),
+            }
+        }
+    }
+
+    // send the backup in dir to the server, which replaces all it holds with it
+    pub fn restore(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        info!(dir = dir.to_string_lossy().as_ref(), "client restore");
+        send_files(dir, |name, chunk| {
+            self.send_request(KSP::RestoreFile(name, chunk))?;
+            match self.get_response()? {
+                Response::Ok(()) => Ok(()),
+                Response::Err(s) => Err(KvsError::RequestError(s)),
+                _ => panic!("unexpected resp type"),
+            }
+        })?;
+        self.send_request(KSP::Restore(()))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Ok(()) => Ok(()),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
+    // watch key, or the keys with it as a prefix, for sets and removes,
+    // starting after the change numbered since if given, or from now on;
+    // the connection is given over to the watch, which ends once dropped
+    pub fn watch(
+        mut self,
+        key: impl Into<Vec<u8>>,
+        prefix: bool,
+        since: Option<u64>,
+    ) -> Result<Watch> {
+        let key = key.into();
+        info!(key = lossy(&key).as_ref(), prefix, since, "client watch");
+        self.send_request(KSP::Watch(key, prefix, since))?;
+        info!("client waiting for resp");
+        match self.get_response()? {
+            Response::Watching(seq) => Ok(Watch {
+                client: Some(self),
+                seq,
+            }),
+            Response::Err(s) => Err(KvsError::RequestError(s)),
+            _ => panic!("unexpected resp type"),
+        }
+    }
+
     fn send_request(&mut self, request: KSP) -> Result<()> {
         let bytes = to_bytes(request)?;
         self.stream
@@ -69,6 +388,46 @@ impl KvsClient {
     }
 
     fn get_response(&mut self) -> Result<Response> {
-        Response::deserialize(&mut self.reader).map_err(KvsError::KSPSerdeError)
+        read_message(&mut self.reader)?.ok_or_else(|| {
+            KvsError::IoError(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "server closed the connection",
+            ))
+        })
+    }
+}
+
+// the changes pushed by the server, blocking until the next one comes;
+// the seq of the last change seen resumes the watch on another connection
+pub struct Watch {
+    // None once the watch has failed
+    client: Option<KvsClient>,
+    // the sequence number the watch started after
+    seq: u64,
+}
+
+impl Watch {
+    pub fn started_after(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        let client = self.client.as_mut()?;
+        let result = match client.get_response() {
+            Ok(Response::Change(change)) => return Some(Ok(change)),
+            Ok(Response::Err(s)) => Err(KvsError::RequestError(s)),
+            Ok(_) => panic!("unexpected resp type"),
+            Err(e) => Err(e),
+        };
+        self.client = None;
+        Some(result)
     }
 }
+
+fn lossy(bytes: &[u8]) -> std::borrow::Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
@@ -1,4 +1,4 @@
-use std::{str::Utf8Error, string::FromUtf8Error};
+use std::string::FromUtf8Error;
 
 use thiserror::Error;
 
@@ -21,15 +21,21 @@ pub enum KvsError {
     #[error("invalid engine {0}")]
     InvalidEngine(String),
 
+    #[error("invalid durability {0}")]
+    InvalidDurability(String),
+
+    #[error("invalid compression {0}")]
+    InvalidCompression(String),
+
+    #[error("invalid compaction policy {0}")]
+    InvalidCompactionPolicy(String),
+
     #[error("invalid addr {0}")]
     InvalidAddr(String),
 
     #[error("fail to connect to server {0}")]
     ServerConnFail(String),
 
-    #[error("fail to serde {0}")]
-    KSPSerdeError(#[from] serde_json::Error),
-
     #[error("unexpected response {0}")]
     RequestError(String),
 
@@ -38,4 +44,54 @@ pub enum KvsError {
 
     #[error("fail to convert Vec<u8> into String")]
     Utf8(#[from] FromUtf8Error),
+
+    #[error("transaction conflict, a key it read has changed")]
+    Conflict,
+
+    #[error("no transaction in progress")]
+    NoTransaction,
+
+    #[error("a transaction is already in progress")]
+    TransactionInProgress,
+
+    #[error("the store is opened read-only")]
+    ReadOnly,
+
+    #[error("the store at {0} is locked by another process")]
+    Locked(String),
+
+    #[error("no store at {0}")]
+    StoreNotFound(String),
+
+    #[error("a store already exists at {0}")]
+    StoreExists(String),
+
+    #[error("{0} is not supported by this engine")]
+    Unsupported(String),
+
+    #[error("no snapshot {0}")]
+    SnapshotNotFound(u64),
+
+    #[error("log {0} named by the manifest is missing")]
+    MissingLog(u32),
+
+    #[error("log format {0} is not supported")]
+    UnsupportedFormat(u32),
+
+    #[error("log {0} is in no format this store recognizes")]
+    UnrecognizedLog(u32),
+
+    #[error("corrupted record in log {file_id} at offset {offset}")]
+    Corruption { file_id: u32, offset: u64 },
+
+    #[error(
+        "the changes since {seq} are gone, compaction has dropped those up to {compacted_seq}"
+    )]
+    ChangesCompacted { seq: u64, compacted_seq: u64 },
+
+    #[error("no backup has been sent over the connection")]
+    NoBackup,
+
+    #[error("{0} is not the name of a file in a backup")]
+    InvalidFileName(String),
 }
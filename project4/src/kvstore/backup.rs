@@ -0,0 +1,165 @@
+// a backup is a data dir of its own: the logs of the store as of one moment and a manifest naming them,
+// so it opens as a store as well as it restores into one.
+// the sealed logs and their hints never change once written, compaction only removes them,
+// so they are hard linked where the file system allows and copied otherwise,
+// while the active log, still appended to, is copied up to where the backup was taken
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use super::hint::hint_path_from_id;
+use super::manifest::{load_manifest, write_manifest};
+use super::{path_from_id, FileID};
+use crate::kvserror::{KvsError, Result};
+
+// copy the given logs, the active one last, of which only the first active_len bytes are taken
+pub(super) fn backup_logs(
+    log_dir_path: &Path,
+    backup_dir: &Path,
+    logs: &[FileID],
+    active_len: u64,
+    compacted_seq: u64,
+) -> Result<()> {
+    let (active_file_id, sealed_file_ids) = logs.split_last().expect("no active log");
+    for file_id in sealed_file_ids.iter().copied() {
+        link_log(log_dir_path, file_id, backup_dir, file_id)?;
+    }
+    copy_prefix(
+        &path_from_id(log_dir_path, *active_file_id),
+        &path_from_id(backup_dir, *active_file_id),
+        active_len,
+    )?;
+    // the backup is complete once its manifest is written
+    write_manifest(backup_dir, logs.to_vec(), compacted_seq)
+}
+
+// bring the logs of a backup into the data dir under fresh ids from first_file_id on,
+// returns the new ids in time order, the logs being all sealed from then on,
+// and the sequence number up to which the backup had been compacted
+pub(super) fn restore_logs(
+    backup_dir: &Path,
+    log_dir_path: &Path,
+    first_file_id: FileID,
+) -> Result<(Vec<FileID>, u64)> {
+    let manifest = load_manifest(backup_dir)?
+        .ok_or_else(|| KvsError::StoreNotFound(backup_dir.display().to_string()))?;
+    let (active_file_id, sealed_file_ids) = match manifest.logs.split_last() {
+        Some(logs) => logs,
+        None => return Ok((Vec::new(), manifest.compacted_seq)),
+    };
+    let mut restored = Vec::with_capacity(manifest.logs.len());
+    for (file_id, new_file_id) in sealed_file_ids.iter().copied().zip(first_file_id..) {
+        link_log(backup_dir, file_id, log_dir_path, new_file_id)?;
+        restored.push(new_file_id);
+    }
+    // the active log of the backup may be written to if the backup is opened as a store,
+    // so it is never linked
+    let new_file_id = first_file_id + sealed_file_ids.len() as FileID;
+    let active_path = path_from_id(backup_dir, *active_file_id);
+    match fs::metadata(&active_path) {
+        Ok(metadata) => {
+            let new_path = path_from_id(log_dir_path, new_file_id);
+            copy_prefix(&active_path, &new_path, metadata.len())?;
+            restored.push(new_file_id);
+        }
+        // a crash right after the manifest named a new active log may leave it uncreated
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok((restored, manifest.compacted_seq))
+}
+
+// a sealed log and its hint, if it has one
+fn link_log(from_dir: &Path, file_id: FileID, to_dir: &Path, new_file_id: FileID) -> Result<()> {
+    let log_path = path_from_id(from_dir, file_id);
+    if !log_path.is_file() {
+        return Err(KvsError::MissingLog(file_id));
+    }
+    link_or_copy(&log_path, &path_from_id(to_dir, new_file_id))?;
+    let hint_path = hint_path_from_id(from_dir, file_id);
+    if hint_path.is_file() {
+        link_or_copy(&hint_path, &hint_path_from_id(to_dir, new_file_id))?;
+    }
+    Ok(())
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    // across file systems, among others
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    File::open(to)?.sync_all()?;
+    Ok(())
+}
+
+fn copy_prefix(from: &Path, to: &Path, len: u64) -> Result<()> {
+    let mut to_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(to)?;
+    io::copy(&mut File::open(from)?.take(len), &mut to_file)?;
+    to_file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::kvstore::{CompactionPolicy, KvStore, KvStoreOptions};
+    use crate::{KvsEngine, KvsError};
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf) -> KvStore {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(2));
+        KvStore::open_with(dir, options).unwrap()
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let (dir, backup_dir, other_dir) = (
+            store_dir("backup-store"),
+            store_dir("backup"),
+            store_dir("backup-other"),
+        );
+        let store = open(&dir);
+        for i in 0..200 {
+            store
+                .set(format!("k{}", i % 50), format!("v{}", i))
+                .unwrap();
+        }
+        store.backup_to(&backup_dir).unwrap();
+        // the backup keeps the store as it was
+        store.set("k0".to_owned(), "after".to_owned()).unwrap();
+        assert!(matches!(
+            store.backup_to(&backup_dir),
+            Err(KvsError::StoreExists(_))
+        ));
+        drop(store);
+
+        let other = open(&other_dir);
+        other.set("other".to_owned(), "x".to_owned()).unwrap();
+        other.restore_from(&backup_dir).unwrap();
+        for store in [KvStore::open(&backup_dir).unwrap(), other] {
+            assert_eq!(store.get("other".to_owned()).unwrap(), None);
+            for i in 150..200 {
+                let value = store.get(format!("k{}", i % 50)).unwrap();
+                assert_eq!(value, Some(format!("v{}", i)));
+            }
+        }
+        // the restored store starts over from the backup
+        assert_eq!(
+            open(&other_dir).get("k0".to_owned()).unwrap(),
+            Some("v150".to_owned())
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// the values read lately are cached by key, each tagged with the version of the key it was read at.
+// a set or a remove gives the key a new version, so a cached value of an older one is never returned,
+// while compaction moves a record without changing the version of its key, keeping its value cached.
+// the cache holds up to its capacity of key and value bytes, evicting the least recently used
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    // the key and value bytes cached, up to the capacity
+    pub bytes: u64,
+    pub capacity: u64,
+}
+
+impl CacheStats {
+    // the share of lookups the cache answered, 0 before any
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+// shared by every handle of a store
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, CachedValue>,
+    // the keys by the tick of their last use, the least recently used first
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    bytes: usize,
+}
+
+#[derive(Debug)]
+struct CachedValue {
+    version: u64,
+    value: Vec<u8>,
+    tick: u64,
+}
+
+impl ValueCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // the value of key at version, if cached
+    pub(super) fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let value = self.lru.lock().unwrap().get(key, version);
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(super) fn insert(&self, key: &[u8], version: u64, value: &[u8]) {
+        // a value which would evict everything else is not worth caching
+        if key.len() + value.len() > self.capacity / 2 {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.insert(key.to_vec(), version, value.to_vec());
+        while lru.bytes > self.capacity {
+            lru.evict();
+        }
+    }
+
+    // drop every value, for when the versions of the keys start over
+    pub(super) fn clear(&self) {
+        *self.lru.lock().unwrap() = Lru::default();
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len() as u64,
+            bytes: lru.bytes as u64,
+            capacity: self.capacity as u64,
+        }
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let cached = self.entries.get_mut(key)?;
+        if cached.version != version {
+            // the key has been written since, the value is of no use any more
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let key = self.recency.remove(&cached.tick).unwrap();
+        cached.tick = self.tick;
+        let value = cached.value.clone();
+        self.recency.insert(self.tick, key);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, version: u64, value: Vec<u8>) {
+        self.remove(&key);
+        self.tick += 1;
+        self.bytes += key.len() + value.len();
+        self.recency.insert(self.tick, key.clone());
+        let cached = CachedValue {
+            version,
+            value,
+            tick: self.tick,
+        };
+        self.entries.insert(key, cached);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.recency.remove(&cached.tick);
+            self.bytes -= key.len() + cached.value.len();
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, key)) = self.recency.pop_first() {
+            let cached = self.entries.remove(&key).unwrap();
+            self.bytes -= key.len() + cached.value.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::ValueCache;
+    use crate::kvstore::{CompactionPolicy, KvStore, KvStoreOptions};
+    use crate::KvsEngine;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn hits_and_misses(store: &KvStore) -> (u64, u64) {
+        let stats = store.cache_stats();
+        (stats.hits, stats.misses)
+    }
+
+    #[test]
+    fn test_lru() {
+        let cache = ValueCache::new(30);
+        cache.insert(b"a", 1, &[0; 9]);
+        cache.insert(b"b", 2, &[1; 9]);
+        cache.insert(b"c", 3, &[2; 9]);
+        // a is used again, so b is the least recently used once d comes in
+        assert_eq!(cache.get(b"a", 1), Some(vec![0; 9]));
+        cache.insert(b"d", 4, &[3; 9]);
+        assert_eq!(cache.get(b"b", 2), None);
+        assert_eq!(cache.get(b"c", 3), Some(vec![2; 9]));
+
+        // a value cached at another version is stale
+        assert_eq!(cache.get(b"a", 5), None);
+        assert_eq!(cache.get(b"c", 3), Some(vec![2; 9]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 2));
+        assert_eq!((stats.entries, stats.bytes), (2, 20));
+
+        // too large to cache
+        cache.insert(b"e", 6, &[4; 30]);
+        assert_eq!(cache.get(b"e", 6), None);
+    }
+
+    // a set or a remove gives the key a new version, so its cached value is not returned
+    #[test]
+    fn test_store_cache_invalidation() {
+        let options = KvStoreOptions::new().cache_capacity(1024);
+        let store = KvStore::open_with(store_dir("cache-invalidation"), options).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(hits_and_misses(&store), (0, 1));
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(hits_and_misses(&store), (1, 1));
+
+        store.set("a".to_owned(), "2".to_owned()).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(hits_and_misses(&store), (1, 2));
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(hits_and_misses(&store), (2, 2));
+
+        // a removed key is not looked up in the cache at all
+        store.remove("a".to_owned()).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), None);
+        assert_eq!(hits_and_misses(&store), (2, 2));
+        store.set("a".to_owned(), "3".to_owned()).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(hits_and_misses(&store), (2, 3));
+    }
+
+    // compaction moves records without changing versions: the cached values stay cached,
+    // the others are read from where the records were moved to
+    #[test]
+    fn test_store_cache_across_compaction() {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(1))
+            .cache_capacity(64 * 1024);
+        let store = KvStore::open_with(store_dir("cache-compaction"), options).unwrap();
+        for i in 0..50 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        // only the even keys are cached
+        for i in (0..50).step_by(2) {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+        assert_eq!(hits_and_misses(&store), (0, 25));
+        let logs = store.segment_stats();
+        // seal the logs holding the keys, which are compacted right away
+        for i in 0..200 {
+            store.set(format!("x{}", i), format!("y{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(store.stats().unwrap().compactions > 0);
+        assert!(store
+            .segment_stats()
+            .iter()
+            .all(|stats| stats.file_id != logs[0].file_id));
+
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+        assert_eq!(hits_and_misses(&store), (25, 50));
+        store.set("k0".to_owned(), "w0".to_owned()).unwrap();
+        assert_eq!(store.get("k0".to_owned()).unwrap(), Some("w0".to_owned()));
+        assert_eq!(hits_and_misses(&store), (25, 51));
+    }
+}
@@ -0,0 +1,334 @@
+// the change feed reads the logs in time order, passing on the commands numbered
+// after its position and following the active log as it grows.
+// the commands of the logs never compacted are in sequence order, while compaction
+// drops commands up to the store's compacted_seq, so a feed which falls behind it
+// fails with KvsError::ChangesCompacted rather than skip changes.
+// commands written before logs carried sequence numbers are numbered 0 and never passed on.
+// a feed which has caught up waits on the WriteSignal of the store rather than poll the logs
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
+
+use super::record::{Next, RecordReader};
+use super::{path_from_id, Command, FileID, KvWriter};
+use crate::kvserror::{KvsError, Result};
+use crate::Change;
+
+pub struct KvStoreChanges {
+    writer: Arc<RwLock<KvWriter>>,
+    log_dir_path: PathBuf,
+    // the sequence number of the last command read
+    seq: u64,
+    log: Option<FeedLog>,
+    // the log read last, the next one comes after it
+    last_file_id: Option<FileID>,
+    // the changes of the command read last which are yet to be passed on
+    pending: VecDeque<Change>,
+}
+
+struct FeedLog {
+    file_id: FileID,
+    records: RecordReader<BufReader<File>>,
+    // nothing is appended to a sealed log, its end is the end of it
+    sealed: bool,
+}
+
+impl KvStoreChanges {
+    pub(super) fn new(
+        writer: &Arc<RwLock<KvWriter>>,
+        log_dir_path: &Path,
+        seq: u64,
+    ) -> Result<Self> {
+        let changes = Self {
+            writer: writer.clone(),
+            log_dir_path: log_dir_path.to_owned(),
+            seq,
+            log: None,
+            last_file_id: None,
+            pending: VecDeque::new(),
+        };
+        changes.check_compacted(writer.read().unwrap().compacted_seq)?;
+        Ok(changes)
+    }
+
+    fn check_compacted(&self, compacted_seq: u64) -> Result<()> {
+        match self.seq < compacted_seq {
+            true => Err(KvsError::ChangesCompacted {
+                seq: self.seq,
+                compacted_seq,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    // read on until there are changes to pass on, false once caught up with the store
+    fn read_more(&mut self) -> Result<bool> {
+        loop {
+            let log = match self.log.as_mut() {
+                Some(log) => log,
+                None => match self.open_next_log()? {
+                    true => continue,
+                    false => return Ok(false),
+                },
+            };
+            let offset = log.records.offset();
+            match log.records.next_record()? {
+                Next::Record { seq, command, .. } => {
+                    if seq > self.seq {
+                        self.seq = seq;
+                        push_changes(&mut self.pending, seq, command);
+                        return Ok(true);
+                    }
+                }
+                // the end of the active log, or a record being appended to it
+                Next::End | Next::Torn { .. } if !log.sealed => {
+                    log.records.seek(offset)?;
+                    if log.file_id == self.writer.read().unwrap().active_file_id {
+                        return Ok(false);
+                    }
+                    // sealed in the meantime, every record is in place by now
+                    log.sealed = true;
+                }
+                Next::End => {
+                    self.last_file_id = Some(log.file_id);
+                    self.log = None;
+                }
+                Next::Torn { offset } | Next::Corrupt { offset, .. } => {
+                    return Err(KvsError::Corruption {
+                        file_id: log.file_id,
+                        offset,
+                    })
+                }
+            }
+        }
+    }
+
+    // move on to the first log after the last one read which may hold changes to pass on,
+    // false if there is none
+    fn open_next_log(&mut self) -> Result<bool> {
+        let (next, compacted_seq) = {
+            let writer = self.writer.read().unwrap();
+            // a sealed log holding no command after the position of the feed is skipped unread
+            let next = writer
+                .log_ids()
+                .into_iter()
+                .filter(|file_id| self.last_file_id.is_none_or(|last| *file_id > last))
+                .find(|file_id| {
+                    *file_id == writer.active_file_id
+                        || writer
+                            .log_seqs
+                            .get(file_id)
+                            .is_none_or(|seqs| seqs.last > self.seq)
+                })
+                .map(|file_id| (file_id, file_id != writer.active_file_id));
+            (next, writer.compacted_seq)
+        };
+        self.check_compacted(compacted_seq)?;
+        let (file_id, sealed) = match next {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        let file = match File::open(path_from_id(&self.log_dir_path, file_id)) {
+            Ok(file) => file,
+            // compacted since, which only matters if it held changes not passed on yet
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.check_compacted(self.writer.read().unwrap().compacted_seq)?;
+                self.last_file_id = Some(file_id);
+                return Ok(true);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.log = Some(FeedLog {
+            file_id,
+            records: RecordReader::new(BufReader::new(file), file_id)?,
+            sealed,
+        });
+        Ok(true)
+    }
+}
+
+fn push_changes(pending: &mut VecDeque<Change>, seq: u64, command: Command) {
+    let (key, value) = match command {
+        Command::Set(key, value) | Command::SetEx(key, value, _) => (key, Some(value)),
+        Command::Rm(key) => (key, None),
+        Command::Batch(commands) => {
+            for command in commands {
+                push_changes(pending, seq, command);
+            }
+            return;
+        }
+    };
+    pending.push_back(Change { seq, key, value });
+}
+
+// returns None once caught up with the store, and the changes appended since when polled again
+impl Iterator for KvStoreChanges {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Some(Ok(change));
+            }
+            match self.read_more() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// raised by the writer each time it appends a command
+pub(super) struct WriteSignal {
+    written_seq: Mutex<u64>,
+    written: Condvar,
+}
+
+impl WriteSignal {
+    pub(super) fn new(written_seq: u64) -> Self {
+        Self {
+            written_seq: Mutex::new(written_seq),
+            written: Condvar::new(),
+        }
+    }
+
+    pub(super) fn notify(&self, seq: u64) {
+        let mut written_seq = self.written_seq.lock().unwrap();
+        *written_seq = (*written_seq).max(seq);
+        self.written.notify_all();
+    }
+
+    // block until a command after seq is appended or timeout has passed,
+    // and return the sequence number of the last command appended
+    pub(super) fn wait_after(&self, seq: u64, timeout: Duration) -> u64 {
+        let written_seq = self.written_seq.lock().unwrap();
+        let (written_seq, _) = self
+            .written
+            .wait_timeout_while(written_seq, timeout, |written_seq| *written_seq <= seq)
+            .unwrap();
+        *written_seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kvstore::record::FILE_HEADER_LEN;
+    use crate::kvstore::{path_from_id, CompactionPolicy, KvStore, KvStoreOptions};
+    use crate::{Change, KvsEngine, KvsError, WriteBatch};
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf, policy: CompactionPolicy) -> KvStore {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(policy);
+        KvStore::open_with(dir, options).unwrap()
+    }
+
+    fn change(seq: u64, key: &str, value: Option<&str>) -> Change {
+        Change {
+            seq,
+            key: key.as_bytes().to_vec(),
+            value: value.map(|value| value.as_bytes().to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let dir = store_dir("changes");
+        let store = open(&dir, CompactionPolicy::Disabled);
+        let mut changes = store.changes_since(0).unwrap();
+        assert!(changes.next().is_none());
+
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("b", "2").remove("a");
+        store.write_batch(batch).unwrap();
+        // polled again once caught up
+        let read: Vec<_> = changes.by_ref().map(|change| change.unwrap()).collect();
+        assert_eq!(
+            read,
+            [
+                change(1, "a", Some("1")),
+                change(2, "b", Some("2")),
+                change(2, "a", None)
+            ]
+        );
+
+        // across sealed logs
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        let seqs: Vec<_> = changes.map(|change| change.unwrap().seq).collect();
+        assert_eq!(seqs, (3..103).collect::<Vec<_>>());
+        drop(store);
+
+        // the sequence numbers go on from where they were after a reopen
+        let store = open(&dir, CompactionPolicy::SealedLogs(1));
+        let mut from_middle = store.changes_since(50).unwrap();
+        assert_eq!(
+            from_middle.next().unwrap().unwrap(),
+            change(51, "k48", Some("v48"))
+        );
+        let mut changes = store.changes_since(102).unwrap();
+        store.remove("b".to_owned()).unwrap();
+        assert_eq!(changes.next().unwrap().unwrap(), change(103, "b", None));
+
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("w{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(matches!(
+            store.changes_since(50).map(|_| ()),
+            Err(KvsError::ChangesCompacted { seq: 50, .. })
+        ));
+        assert!(matches!(
+            from_middle.find_map(|change| change.err()),
+            Some(KvsError::ChangesCompacted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_changes_skip_older_logs() {
+        let dir = store_dir("changes-skip");
+        let store = open(&dir, CompactionPolicy::Disabled);
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        drop(store);
+
+        // the records of the oldest log are garbled, while its hint still matches it
+        let log_path = path_from_id(&dir, 0);
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        bytes[FILE_HEADER_LEN as usize..].fill(0xff);
+        std::fs::write(&log_path, bytes).unwrap();
+
+        let store = open(&dir, CompactionPolicy::Disabled);
+        assert!(matches!(
+            store
+                .changes_since(0)
+                .unwrap()
+                .find_map(|change| change.err()),
+            Some(KvsError::Corruption { file_id: 0, .. })
+        ));
+        let seqs: Vec<_> = store
+            .changes_since(90)
+            .unwrap()
+            .map(|change| change.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, (91..101).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,603 @@
+// compaction runs on a background thread:
+// it scans the sealed logs picked by the compaction policy and copies the records
+// which the index still points at into fresh logs, while writers keep appending to the active log,
+// then points the index at the fresh logs and retires the sealed ones.
+// as only some of the sealed logs may be compacted, the removals in them which shadow
+// values in older logs left alone are kept as tombstones.
+// the records copied keep their sequence numbers, but the changes the inputs went through are lost
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Instant;
+
+use crossbeam_channel::Receiver;
+use tracing::{error, info};
+
+use super::compression::Compressor;
+use super::hint::{remove_hint, write_hint, HintEntry, SeqRange};
+use super::manifest::write_manifest;
+use super::record::{encode_record, file_header, Next, RecordReader, FILE_HEADER_LEN};
+use super::{compact_path_from_id, last_writes, path_from_id, Command, FileID, KvWriter, ValuePos};
+use crate::kvserror::Result;
+use crate::ttl::now_millis;
+
+pub(super) struct CompactionTask {
+    // the sealed logs to compact, in time order
+    pub(super) inputs: Vec<FileID>,
+    // the ids reserved for the compacted logs, in time order
+    pub(super) outputs: Vec<FileID>,
+    // the oldest sealed log left out of the inputs,
+    // the tombstones of the inputs which are newer than it are kept
+    pub(super) oldest_retained: Option<FileID>,
+    // the size of the logs to split the output into
+    pub(super) segment_size: u64,
+}
+
+// the compactor stops once every handle of the store is dropped
+pub(super) fn spawn_compactor(
+    writer: Weak<RwLock<KvWriter>>,
+    retire_epoch: Arc<AtomicU64>,
+    log_dir_path: PathBuf,
+    compressor: Compressor,
+    tasks: Receiver<CompactionTask>,
+) {
+    thread::spawn(move || {
+        for task in tasks {
+            let writer = match writer.upgrade() {
+                Some(writer) => writer,
+                None => break,
+            };
+            let started = Instant::now();
+            let result = compact_logs(&writer, &log_dir_path, &compressor, &task);
+            {
+                let mut writer = writer.write().unwrap();
+                match result {
+                    Ok(()) => {
+                        writer.compactions += 1;
+                        writer.last_compaction = Some(started.elapsed());
+                    }
+                    Err(e) => error!(error = e.to_string().as_str(), "fail to compact logs"),
+                }
+                writer.compacting = false;
+            }
+            // readers shall not keep the retired logs open
+            retire_epoch.fetch_add(1, Ordering::Release);
+        }
+    });
+}
+
+fn compact_logs(
+    writer: &RwLock<KvWriter>,
+    log_dir_path: &Path,
+    compressor: &Compressor,
+    task: &CompactionTask,
+) -> Result<()> {
+    info!(inputs = task.inputs.len(), "compaction begins");
+    let now = now_millis();
+    // the live records of the inputs are those the index points at
+    let live_positions: HashMap<_, _> = {
+        let writer = writer.read().unwrap();
+        writer
+            .log_index
+            .iter()
+            .filter(|(_, pos)| task.inputs.binary_search(&pos.file_id).is_ok())
+            .map(|(key, pos)| (key.clone(), pos.clone()))
+            .collect()
+    };
+
+    let mut outputs = task.outputs.iter().copied();
+    let mut log = CompactedLog::create(
+        log_dir_path,
+        outputs.next().expect("no log reserved for compaction"),
+        compressor,
+    )?;
+    let mut new_positions = Vec::with_capacity(live_positions.len());
+    // removed or expired keys whose older values may still sit in a log which is not compacted,
+    // along with the sequence number of their removal
+    let mut tombstones = BTreeMap::new();
+    let mut compacted = Vec::new();
+    let mut inputs_last_seq = 0;
+    // every input is read front to back
+    for file_id in task.inputs.iter().copied() {
+        let shadows_older = task.oldest_retained.is_some_and(|oldest| oldest < file_id);
+        let file = File::open(path_from_id(log_dir_path, file_id))?;
+        let mut records = RecordReader::new(BufReader::new(file), file_id)?;
+        while let Next::Record {
+            offset,
+            seq,
+            command,
+            ..
+        } = records.next_record()?
+        {
+            inputs_last_seq = inputs_last_seq.max(seq);
+            let commands = match command {
+                Command::Batch(commands) => last_writes(commands),
+                command => vec![command],
+            };
+            for command in commands {
+                let (key, value) = match command {
+                    Command::Set(key, value) | Command::SetEx(key, value, _) => (key, value),
+                    Command::Rm(key) => {
+                        if shadows_older && !live_positions.contains_key(&key) {
+                            tombstones.insert(key, seq);
+                        }
+                        continue;
+                    }
+                    Command::Batch(_) => continue,
+                };
+                match live_positions.get(&key) {
+                    Some(pos) if pos.file_id == file_id && pos.offset == offset => {
+                        if pos.is_expired(now) {
+                            if shadows_older {
+                                tombstones.insert(key, seq);
+                            }
+                            continue;
+                        }
+                        let new_pos = log.append(seq, &key, value, pos.expire_at)?;
+                        new_positions.push((key, new_pos));
+                    }
+                    _ => continue,
+                }
+                if log.len > task.segment_size {
+                    // whatever is left goes to the last reserved log
+                    if let Some(file_id) = outputs.next() {
+                        compacted.extend(log.seal()?);
+                        log = CompactedLog::create(log_dir_path, file_id, compressor)?;
+                    }
+                }
+            }
+        }
+    }
+    // the compacted logs come after every log which is not compacted,
+    // so a key set again in one of those must not be removed by a tombstone
+    {
+        let writer = writer.read().unwrap();
+        let first_output = task.outputs[0];
+        tombstones.retain(|key, _| {
+            writer.log_index.get(key).is_none_or(|pos| {
+                pos.file_id > first_output || task.inputs.binary_search(&pos.file_id).is_ok()
+            })
+        });
+    }
+    for (key, seq) in tombstones {
+        log.append_tombstone(seq, &key)?;
+    }
+    compacted.extend(log.seal()?);
+
+    // swap in the new positions of the records which are still live,
+    // those updated or removed in the meantime are left alone
+    let unpinned = {
+        let mut writer = writer.write().unwrap();
+        let writer = &mut *writer;
+        // the compacted logs replace the inputs once the manifest says so,
+        // until then a crash leaves them as leftovers
+        let mut logs: Vec<_> = writer
+            .sealed_file_ids
+            .iter()
+            .copied()
+            .filter(|file_id| task.inputs.binary_search(file_id).is_err())
+            .chain(compacted.iter().map(|output| output.file_id))
+            .collect();
+        logs.sort_unstable();
+        logs.push(writer.active_file_id);
+        let compacted_seq = writer.compacted_seq.max(inputs_last_seq);
+        write_manifest(log_dir_path, logs, compacted_seq)?;
+        writer.compacted_seq = compacted_seq;
+        for (key, new_pos) in new_positions {
+            if let Some(pos) = writer.log_index.get_mut(&key) {
+                if task.inputs.binary_search(&pos.file_id).is_ok() {
+                    // moving a record does not change the key
+                    let new_pos = new_pos.with_version(pos.version);
+                    writer.segments.drop_live(pos);
+                    writer.segments.add_live(&new_pos);
+                    *pos = new_pos;
+                }
+            }
+        }
+        // the keys which expired in the inputs are gone with them
+        let expired: Vec<_> = writer
+            .log_index
+            .iter()
+            .filter(|(_, pos)| {
+                task.inputs.binary_search(&pos.file_id).is_ok() && pos.is_expired(now)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            if let Some(pos) = writer.log_index.remove(&key) {
+                writer.segments.drop_live(&pos);
+            }
+        }
+        for file_id in task.inputs.iter() {
+            writer.sealed_file_ids.remove(file_id);
+            writer.legacy_file_ids.remove(file_id);
+            writer.segments.remove(*file_id);
+            writer.log_seqs.remove(file_id);
+        }
+        for output in compacted {
+            writer.sealed_file_ids.insert(output.file_id);
+            writer.segments.add_total(output.file_id, output.len);
+            writer.log_seqs.insert(output.file_id, output.seqs);
+        }
+        // the logs pinned by snapshots are removed once released
+        for file_id in task.inputs.iter() {
+            if writer.pinned_logs.contains_key(file_id) {
+                writer.retired_file_ids.insert(*file_id);
+            }
+        }
+        task.inputs
+            .iter()
+            .copied()
+            .filter(|file_id| !writer.retired_file_ids.contains(file_id))
+            .collect::<Vec<_>>()
+    };
+
+    // nothing refers to the compacted logs any more
+    for file_id in unpinned {
+        retire_log(log_dir_path, file_id)?;
+    }
+    info!(inputs = task.inputs.len(), "compaction finishes");
+    Ok(())
+}
+
+// remove a log which nothing refers to any more together with its hint
+pub(super) fn retire_log(log_dir_path: &Path, file_id: FileID) -> Result<()> {
+    remove_hint(log_dir_path, file_id)?;
+    remove_file(path_from_id(log_dir_path, file_id))?;
+    Ok(())
+}
+
+// a compacted log is written under a temporary name
+// and only shows up as a log once it is complete
+struct CompactedLog {
+    file_id: FileID,
+    tmp_path: PathBuf,
+    log_dir_path: PathBuf,
+    buf_writer: BufWriter<File>,
+    compressor: Compressor,
+    len: u64,
+    seqs: SeqRange,
+    hint: Vec<HintEntry>,
+}
+
+// a compacted log moved into place
+struct CompactedOutput {
+    file_id: FileID,
+    len: u64,
+    seqs: SeqRange,
+}
+
+impl CompactedLog {
+    fn create(log_dir_path: &Path, file_id: FileID, compressor: &Compressor) -> Result<Self> {
+        let tmp_path = compact_path_from_id(log_dir_path, file_id);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut buf_writer = BufWriter::new(file);
+        buf_writer.write_all(&file_header())?;
+        Ok(Self {
+            file_id,
+            tmp_path,
+            log_dir_path: log_dir_path.to_owned(),
+            buf_writer,
+            compressor: compressor.clone(),
+            len: FILE_HEADER_LEN,
+            seqs: SeqRange::default(),
+            hint: Vec::new(),
+        })
+    }
+
+    fn append(
+        &mut self,
+        seq: u64,
+        key: &[u8],
+        value: Vec<u8>,
+        expire_at: Option<u64>,
+    ) -> Result<ValuePos> {
+        let command = match expire_at {
+            Some(expire_at) => Command::SetEx(key.to_vec(), value, expire_at),
+            None => Command::Set(key.to_vec(), value),
+        };
+        let bytes = encode_record(seq, &command, &self.compressor)?;
+        self.seqs.add(seq);
+        self.buf_writer.write_all(bytes.as_slice())?;
+        let pos = ValuePos::new(self.file_id, self.len, bytes.len() as u64).expires_at(expire_at);
+        self.hint.push(HintEntry::Set {
+            key: key.to_vec(),
+            offset: pos.offset,
+            len: pos.len,
+            expire_at,
+        });
+        self.len += pos.len;
+        Ok(pos)
+    }
+
+    fn append_tombstone(&mut self, seq: u64, key: &[u8]) -> Result<()> {
+        let bytes = encode_record(seq, &Command::Rm(key.to_vec()), &self.compressor)?;
+        self.seqs.add(seq);
+        self.buf_writer.write_all(bytes.as_slice())?;
+        self.hint.push(HintEntry::Rm(key.to_vec()));
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    // move the log into place together with its hint,
+    // an empty log is dropped and yields nothing
+    fn seal(mut self) -> Result<Option<CompactedOutput>> {
+        if self.hint.is_empty() {
+            remove_file(&self.tmp_path)?;
+            return Ok(None);
+        }
+        self.buf_writer.flush()?;
+        self.buf_writer.get_ref().sync_all()?;
+        rename(
+            &self.tmp_path,
+            path_from_id(&self.log_dir_path, self.file_id),
+        )?;
+        write_hint(
+            &self.log_dir_path,
+            self.file_id,
+            self.len,
+            self.seqs,
+            &self.hint,
+        )?;
+        Ok(Some(CompactedOutput {
+            file_id: self.file_id,
+            len: self.len,
+            seqs: self.seqs,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde::Serialize;
+
+    use crate::kvstore::record::tests::UNFRAMED_LOG;
+    use crate::kvstore::record::{LogFormat, RecordReader};
+    use crate::kvstore::{path_from_id, CompactionPolicy, KvStore, KvStoreOptions, LogReaders};
+    use crate::{KvsEngine, KvsSnapshot, WriteBatch};
+
+    const KEYS: usize = 50;
+    const ROUNDS: usize = 200;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf, policy: CompactionPolicy) -> KvStore {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(policy);
+        KvStore::open_with(dir, options).unwrap()
+    }
+
+    // the round a value was written in, checking it belongs to key i
+    fn round_of(i: usize, value: &str) -> usize {
+        let (prefix, round) = value.rsplit_once('-').unwrap();
+        assert_eq!(prefix, format!("v{}", i));
+        round.parse().unwrap()
+    }
+
+    // every entry of the index decodes to a value of its own key
+    fn check_index(store: &KvStore) {
+        let writer = store.writer.read().unwrap();
+        let mut readers = LogReaders::default();
+        for (key, pos) in writer.log_index.iter() {
+            readers.read_value(&store.log_dir_path, key, pos).unwrap();
+        }
+    }
+
+    // overwrite every key ROUNDS times while readers keep reading them,
+    // each reader shall see every key at its latest round or a later one
+    fn gets_during_compaction(name: &str, policy: CompactionPolicy) {
+        let dir = store_dir(name);
+        let store = open(&dir, policy);
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut seen = vec![0; KEYS];
+                    while !done.load(Ordering::Acquire) {
+                        for (i, seen) in seen.iter_mut().enumerate() {
+                            if let Some(value) = store.get(format!("k{}", i)).unwrap() {
+                                let round = round_of(i, &value);
+                                assert!(round >= *seen, "k{} went back to round {}", i, round);
+                                *seen = round;
+                            }
+                        }
+                        for (key, value) in store.scan_prefix("k".to_owned(), KEYS).unwrap() {
+                            round_of(key[1..].parse().unwrap(), &value);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 1..=ROUNDS {
+            for i in 0..KEYS {
+                store
+                    .set(format!("k{}", i), format!("v{}-{}", i, round))
+                    .unwrap();
+            }
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // the logs were compacted along the way
+        assert!(store.segment_stats().len() < ROUNDS * KEYS * 20 / 1024 / 2);
+        check_index(&store);
+        for i in 0..KEYS {
+            let value = store.get(format!("k{}", i)).unwrap().unwrap();
+            assert_eq!(round_of(i, &value), ROUNDS);
+        }
+        // and stay consistent once reopened
+        drop(store);
+        thread::sleep(Duration::from_millis(500));
+        let store = open(&dir, policy);
+        check_index(&store);
+        for i in 0..KEYS {
+            let value = store.get(format!("k{}", i)).unwrap().unwrap();
+            assert_eq!(round_of(i, &value), ROUNDS);
+        }
+    }
+
+    #[test]
+    fn test_gets_during_compaction() {
+        gets_during_compaction("compact-all", CompactionPolicy::SealedLogs(1));
+        gets_during_compaction("compact-garbage", CompactionPolicy::GarbageRatio(0.3));
+    }
+
+    // a batch setting a key twice leaves the later value once compacted
+    #[test]
+    fn test_compact_batch_with_duplicate_key() {
+        let dir = store_dir("compact-batch-duplicate");
+        let store = open(&dir, CompactionPolicy::SealedLogs(1));
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").set("a", "3");
+        store.write_batch(batch).unwrap();
+        // seal the log holding the batch, which is compacted right away
+        for i in 0..200 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        check_index(&store);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+
+        drop(store);
+        thread::sleep(Duration::from_millis(500));
+        let store = open(&dir, CompactionPolicy::SealedLogs(1));
+        check_index(&store);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+    }
+
+    // removals in the compacted logs keep shadowing the values in the logs left alone
+    #[test]
+    fn test_removed_keys_stay_removed() {
+        let dir = store_dir("compact-removed");
+        let store = open(&dir, CompactionPolicy::GarbageRatio(0.6));
+        for i in 0..KEYS {
+            store.set(format!("cold{}", i), "x".repeat(40)).unwrap();
+        }
+        for round in 1..=ROUNDS {
+            if round % 4 == 0 && round / 4 <= KEYS / 2 {
+                store.remove(format!("cold{}", round / 4 - 1)).unwrap();
+            }
+            for i in 0..KEYS / 5 {
+                store
+                    .set(format!("k{}", i), format!("v{}-{}", i, round))
+                    .unwrap();
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+        check_index(&store);
+        drop(store);
+        thread::sleep(Duration::from_millis(500));
+        let store = open(&dir, CompactionPolicy::GarbageRatio(0.6));
+        check_index(&store);
+        for i in 0..KEYS {
+            let value = store.get(format!("cold{}", i)).unwrap();
+            assert_eq!(value.is_none(), i < KEYS / 2, "cold{}", i);
+        }
+    }
+
+    // a snapshot keeps reading the logs compacted after it was taken
+    #[test]
+    fn test_snapshot_during_compaction() {
+        let dir = store_dir("compact-snapshot");
+        let store = open(&dir, CompactionPolicy::SealedLogs(1));
+        for i in 0..KEYS {
+            store.set(format!("k{}", i), format!("v{}-0", i)).unwrap();
+        }
+        let snapshot = store.snapshot().unwrap();
+        for round in 1..=ROUNDS / 4 {
+            for i in 0..KEYS {
+                store
+                    .set(format!("k{}", i), format!("v{}-{}", i, round))
+                    .unwrap();
+            }
+            for i in 0..KEYS {
+                let value = snapshot.get(format!("k{}", i)).unwrap().unwrap();
+                assert_eq!(round_of(i, &value), 0);
+            }
+        }
+        check_index(&store);
+    }
+
+    // the commands as the store encoded them before records were framed
+    #[derive(Serialize)]
+    enum UnframedCommand {
+        Set(String, String),
+        Rm(String),
+    }
+
+    fn unframed_record(command: &UnframedCommand) -> Vec<u8> {
+        bson::to_vec(&bson::to_bson(command).unwrap()).unwrap()
+    }
+
+    // a store written before records were framed is rewritten in the current format
+    #[test]
+    fn test_upgrade_unframed_logs() {
+        use UnframedCommand::{Rm, Set};
+        let set = |key: &str, value: &str| Set(key.to_owned(), value.to_owned());
+        let commands = [set("a", "1"), set("b", "2"), Rm("a".to_owned())];
+        let log: Vec<u8> = commands.iter().flat_map(unframed_record).collect();
+        assert!(UNFRAMED_LOG.starts_with(&log));
+
+        let dir = store_dir("compact-unframed");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut log = Vec::new();
+        for round in 0..10 {
+            for i in 0..KEYS {
+                log.extend(unframed_record(&set(
+                    &format!("k{}", i),
+                    &format!("v{}-{}", i, round),
+                )));
+            }
+            log.extend(unframed_record(&Rm(format!("k{}", round))));
+        }
+        std::fs::write(path_from_id(&dir, 0), &log).unwrap();
+
+        let store = open(&dir, CompactionPolicy::SealedLogs(4));
+        thread::sleep(Duration::from_millis(500));
+        let logs = store.writer.read().unwrap().log_ids();
+        assert!(!logs.contains(&0));
+        for file_id in logs {
+            let file = std::fs::File::open(path_from_id(&dir, file_id)).unwrap();
+            let records = RecordReader::new(file, file_id).unwrap();
+            assert_eq!(records.format(), LogFormat::Binary);
+        }
+        drop(store);
+        thread::sleep(Duration::from_millis(500));
+        let store = open(&dir, CompactionPolicy::SealedLogs(4));
+        check_index(&store);
+        for i in 0..KEYS {
+            let value = store.get(format!("k{}", i)).unwrap();
+            // the last round removed k9 after setting it
+            match i == 9 {
+                true => assert_eq!(value, None),
+                false => assert_eq!(round_of(i, &value.unwrap()), 9),
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// the body of a record whose encoded command is at least as long as the threshold
+// is compressed when that saves space, and the record names the codec it was compressed with,
+// so that a log holds compressed and plain records side by side
+// whatever the store is configured with when it reads them.
+// the logs in the bson format stored a compressed payload as a CompressedPayload instead
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::Command;
+use crate::kvserror::Result;
+
+// the codec values are compressed with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Lz4,
+    Snappy,
+}
+
+// the records written since the store was opened, compaction included
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub records: u64,
+    pub compressed_records: u64,
+    // the payload bytes before and after compression
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+impl Compression {
+    pub(super) fn codec_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Snappy => 2,
+        }
+    }
+
+    pub(super) fn from_codec_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Snappy),
+            _ => None,
+        }
+    }
+}
+
+impl CompressionStats {
+    // how many times smaller the payloads got, 1 when nothing was written
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompressedPayload {
+    codec: Compression,
+    #[serde(with = "crate::binary")]
+    payload: Vec<u8>,
+}
+
+// compresses the payloads of a store, shared by its writer and its compactor
+#[derive(Clone, Debug)]
+pub(super) struct Compressor {
+    compression: Compression,
+    threshold: usize,
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    records: AtomicU64,
+    compressed_records: AtomicU64,
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::new(Compression::None, 0)
+    }
+}
+
+impl Compressor {
+    pub(super) fn new(compression: Compression, threshold: usize) -> Self {
+        Self {
+            compression,
+            threshold,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    // the codec and the body to store for the encoded command
+    pub(super) fn compress(&self, body: Vec<u8>) -> Result<(Compression, Vec<u8>)> {
+        let raw_len = body.len() as u64;
+        let stored = match self.compression {
+            Compression::None => None,
+            _ if body.len() < self.threshold => None,
+            codec => Some((codec, compress(codec, &body)?)),
+        };
+        let counters = &self.counters;
+        counters.records.fetch_add(1, Ordering::Relaxed);
+        counters.raw_bytes.fetch_add(raw_len, Ordering::Relaxed);
+        // incompressible bodies are stored as they are
+        let (codec, body) = match stored {
+            Some((codec, stored)) if stored.len() < body.len() => {
+                counters.compressed_records.fetch_add(1, Ordering::Relaxed);
+                (codec, stored)
+            }
+            _ => (Compression::None, body),
+        };
+        counters
+            .stored_bytes
+            .fetch_add(body.len() as u64, Ordering::Relaxed);
+        Ok((codec, body))
+    }
+
+    pub(super) fn stats(&self) -> CompressionStats {
+        let counters = &self.counters;
+        CompressionStats {
+            records: counters.records.load(Ordering::Relaxed),
+            compressed_records: counters.compressed_records.load(Ordering::Relaxed),
+            raw_bytes: counters.raw_bytes.load(Ordering::Relaxed),
+            stored_bytes: counters.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// decode the command of a payload stored in the bson format, None if it is not a valid one
+pub(super) fn decode_payload(payload: &[u8]) -> Option<Command> {
+    if let Ok(command) = bson::from_slice(payload) {
+        return Some(command);
+    }
+    let compressed: CompressedPayload = bson::from_slice(payload).ok()?;
+    let payload = decompress(compressed.codec, &compressed.payload)?;
+    bson::from_slice(&payload).ok()
+}
+
+fn compress(codec: Compression, bytes: &[u8]) -> Result<Vec<u8>> {
+    Ok(match codec {
+        Compression::None => bytes.to_vec(),
+        Compression::Lz4 => lz4_flex::compress_prepend_size(bytes),
+        Compression::Snappy => snap::raw::Encoder::new()
+            .compress_vec(bytes)
+            .map_err(std::io::Error::from)?,
+    })
+}
+
+pub(super) fn decompress(codec: Compression, bytes: &[u8]) -> Option<Vec<u8>> {
+    match codec {
+        Compression::None => Some(bytes.to_vec()),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(bytes).ok(),
+        Compression::Snappy => snap::raw::Decoder::new().decompress_vec(bytes).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_payload, decompress, CompressedPayload, Compression, Compressor};
+    use crate::kvstore::Command;
+
+    #[test]
+    fn test_compress() {
+        let body = b"{\"v\": 1}".repeat(64);
+        for codec in [Compression::Lz4, Compression::Snappy] {
+            let compressor = Compressor::new(codec, 64);
+            let (stored_codec, stored) = compressor.compress(body.clone()).unwrap();
+            assert_eq!(stored_codec, codec);
+            assert!(stored.len() < body.len());
+            assert_eq!(decompress(codec, &stored).unwrap(), body);
+            let byte = codec.codec_byte();
+            assert_eq!(Compression::from_codec_byte(byte), Some(codec));
+
+            // bodies below the threshold stay plain
+            let small = b"small".to_vec();
+            let (codec, stored) = compressor.compress(small.clone()).unwrap();
+            assert_eq!((codec, stored), (Compression::None, small));
+
+            let stats = compressor.stats();
+            assert_eq!((stats.records, stats.compressed_records), (2, 1));
+            assert!(stats.ratio() > 1.0);
+        }
+    }
+
+    #[test]
+    fn test_bson_payloads() {
+        let command = Command::Set(b"k".to_vec(), b"{\"v\": 1}".repeat(64));
+        let payload = bson::to_vec(&bson::to_document(&command).unwrap()).unwrap();
+        assert!(matches!(decode_payload(&payload), Some(Command::Set(_, v)) if v.len() == 512));
+        let compressed = CompressedPayload {
+            codec: Compression::Lz4,
+            payload: lz4_flex::compress_prepend_size(&payload),
+        };
+        let compressed = bson::to_vec(&bson::to_document(&compressed).unwrap()).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert!(matches!(decode_payload(&compressed), Some(Command::Set(_, v)) if v.len() == 512));
+    }
+}
@@ -0,0 +1,44 @@
+// keys whose ttl ran out are removed lazily when they are read,
+// and actively by a background thread for those never read again
+
+use std::sync::{RwLock, Weak};
+use std::thread;
+
+use tracing::{error, info};
+
+use super::KvWriter;
+use crate::ttl::{now_millis, EXPIRY_INTERVAL};
+
+// the most keys removed while holding the writer lock once
+const EXPIRY_BATCH: usize = 1024;
+
+// the expirer stops once every handle of the store is dropped
+pub(super) fn spawn_expirer(writer: Weak<RwLock<KvWriter>>) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_INTERVAL);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        loop {
+            let result = writer
+                .write()
+                .unwrap()
+                .expire_keys(now_millis(), EXPIRY_BATCH);
+            match result {
+                Ok(expired) => {
+                    if expired > 0 {
+                        info!(expired, "expired keys removed");
+                    }
+                    if expired < EXPIRY_BATCH {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!(error = e.to_string().as_str(), "fail to expire keys");
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,251 @@
+// a hint file sits next to every sealed log (`<id>.hint` for `<id>.log`)
+// and lists the index entries of that log in append order,
+// so that opening a store does not need to decode every record again
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use bson::Document;
+use serde::{Deserialize, Serialize};
+
+use super::FileID;
+use crate::kvserror::Result;
+
+pub(super) const HINT_SUFFIX: &str = "hint";
+pub(super) const HINT_TMP_SUFFIX: &str = "hint.tmp";
+
+#[derive(Serialize, Deserialize)]
+struct HintHeader {
+    // the length of the log this hint describes,
+    // a hint whose log has a different length is stale
+    log_len: u64,
+    // the sequence numbers of the first and last commands in the log
+    #[serde(default)]
+    first_seq: u64,
+    #[serde(default)]
+    last_seq: u64,
+    entries: u64,
+}
+
+// the sequence numbers of the first and last commands of a log, 0 for commands without one,
+// so that a change feed skips the logs which hold nothing after its position
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) struct SeqRange {
+    pub(super) first: u64,
+    pub(super) last: u64,
+}
+
+impl SeqRange {
+    pub(super) fn add(&mut self, seq: u64) {
+        if seq == 0 {
+            return;
+        }
+        if self.first == 0 || seq < self.first {
+            self.first = seq;
+        }
+        self.last = self.last.max(seq);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum HintEntry {
+    Set {
+        #[serde(with = "crate::binary")]
+        key: Vec<u8>,
+        offset: u64,
+        len: u64,
+        #[serde(default)]
+        expire_at: Option<u64>,
+    },
+    Rm(#[serde(with = "crate::binary")] Vec<u8>),
+}
+
+// write the hint of a sealed log atomically:
+// the entries go to a temporary file which is renamed once complete
+pub(super) fn write_hint(
+    log_dir_path: &Path,
+    file_id: FileID,
+    log_len: u64,
+    seqs: SeqRange,
+    entries: &[HintEntry],
+) -> Result<()> {
+    let tmp_path = tmp_hint_path_from_id(log_dir_path, file_id);
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let mut buf_writer = BufWriter::new(file);
+
+    let header = HintHeader {
+        log_len,
+        first_seq: seqs.first,
+        last_seq: seqs.last,
+        entries: entries.len() as u64,
+    };
+    buf_writer.write_all(&bson::to_vec(&bson::to_document(&header)?)?)?;
+    for entry in entries {
+        buf_writer.write_all(&bson::to_vec(&bson::to_document(entry)?)?)?;
+    }
+    buf_writer.flush()?;
+    buf_writer.get_ref().sync_all()?;
+
+    fs::rename(tmp_path, hint_path_from_id(log_dir_path, file_id))?;
+    Ok(())
+}
+
+// load the hint of a sealed log along with the sequence numbers of its commands,
+// returns None if the hint is missing or does not match the log,
+// in which case the caller shall replay the log itself
+pub(super) fn load_hint(
+    log_dir_path: &Path,
+    file_id: FileID,
+    log_len: u64,
+) -> Option<(Vec<HintEntry>, SeqRange)> {
+    let file = File::open(hint_path_from_id(log_dir_path, file_id)).ok()?;
+    let mut buf_reader = BufReader::new(file);
+
+    let header: HintHeader =
+        bson::from_document(Document::from_reader(&mut buf_reader).ok()?).ok()?;
+    if header.log_len != log_len {
+        return None;
+    }
+
+    let mut entries = Vec::with_capacity(header.entries as usize);
+    for _ in 0..header.entries {
+        let doc = Document::from_reader(&mut buf_reader).ok()?;
+        let entry: HintEntry = bson::from_document(doc).ok()?;
+        if let HintEntry::Set { offset, len, .. } = entry {
+            if offset + len > log_len {
+                return None;
+            }
+        }
+        entries.push(entry);
+    }
+    // a complete hint ends right after its last entry
+    if Document::from_reader(&mut buf_reader).is_ok() {
+        return None;
+    }
+    let seqs = SeqRange {
+        first: header.first_seq,
+        last: header.last_seq,
+    };
+    Some((entries, seqs))
+}
+
+// remove the hint of a log, if any
+pub(super) fn remove_hint(log_dir_path: &Path, file_id: FileID) -> Result<()> {
+    match fs::remove_file(hint_path_from_id(log_dir_path, file_id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn hint_path_from_id(log_dir_path: &Path, file_id: FileID) -> PathBuf {
+    log_dir_path.join(format!("{}.{}", file_id, HINT_SUFFIX))
+}
+
+fn tmp_hint_path_from_id(log_dir_path: &Path, file_id: FileID) -> PathBuf {
+    log_dir_path.join(format!("{}.{}", file_id, HINT_TMP_SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{hint_path_from_id, load_hint, write_hint, HintEntry};
+    use crate::kvstore::manifest::load_manifest;
+    use crate::kvstore::{path_from_id, CompactionPolicy, FileID, KvStore, KvStoreOptions};
+    use crate::KvsEngine;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &PathBuf) -> KvStore {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::Disabled);
+        KvStore::open_with(dir, options).unwrap()
+    }
+
+    // a store of several sealed logs, along with its oldest log and the length of that log
+    fn sealed_store(name: &str) -> (PathBuf, FileID, u64) {
+        let dir = store_dir(name);
+        let store = open(&dir);
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        drop(store);
+        thread::sleep(Duration::from_millis(200));
+        let logs = load_manifest(&dir).unwrap().unwrap().logs;
+        assert!(logs.len() > 1);
+        let file_id = logs[0];
+        let log_len = fs::metadata(path_from_id(&dir, file_id)).unwrap().len();
+        (dir, file_id, log_len)
+    }
+
+    // rewrite the hint of a log without its entry of k0
+    fn hint_without_k0(dir: &Path, file_id: FileID, log_len: u64, hint_log_len: u64) {
+        let (entries, seqs) = load_hint(dir, file_id, log_len).unwrap();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| !matches!(entry, HintEntry::Set { key, .. } if key == b"k0"))
+            .collect();
+        write_hint(dir, file_id, hint_log_len, seqs, &entries).unwrap();
+    }
+
+    // a matching hint is trusted: the key left out of it is not indexed
+    #[test]
+    fn test_sealed_logs_indexed_from_hints() {
+        let (dir, file_id, log_len) = sealed_store("hint-indexed");
+        hint_without_k0(&dir, file_id, log_len, log_len);
+        let store = open(&dir);
+        assert_eq!(store.get("k0".to_owned()).unwrap(), None);
+        for i in 1..100 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+    }
+
+    // a hint written for another length of its log is stale, the log is replayed instead
+    #[test]
+    fn test_stale_hint_replayed() {
+        let (dir, file_id, log_len) = sealed_store("hint-stale");
+        hint_without_k0(&dir, file_id, log_len, log_len - 1);
+        assert!(load_hint(&dir, file_id, log_len).is_none());
+        let store = open(&dir);
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+    }
+
+    // so is a hint cut short
+    #[test]
+    fn test_truncated_hint_replayed() {
+        let (dir, file_id, log_len) = sealed_store("hint-truncated");
+        hint_without_k0(&dir, file_id, log_len, log_len);
+        let hint_path = hint_path_from_id(&dir, file_id);
+        let hint = fs::read(&hint_path).unwrap();
+        fs::write(&hint_path, &hint[..hint.len() - 10]).unwrap();
+        assert!(load_hint(&dir, file_id, log_len).is_none());
+        let store = open(&dir);
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+    }
+}
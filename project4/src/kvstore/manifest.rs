@@ -0,0 +1,172 @@
+// the manifest (`MANIFEST` in the data dir) names the logs which make up the store,
+// oldest first, the last one being the active log.
+// it is replaced atomically whenever that set changes, that is when the active log is sealed
+// and when a compaction swaps its logs in, so a crash at any point leaves either the old set
+// or the new one, and the logs outside of it are leftovers of a compaction cut short.
+// it also keeps the sequence number up to which compaction has dropped commands,
+// which the records left in the logs may not reach
+
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+use bson::Document;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::hint::{HINT_SUFFIX, HINT_TMP_SUFFIX};
+use super::{sync_dir, FileID, BACKUP_SUFFIX, LOG_SUFFIX};
+use crate::kvserror::Result;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub(super) logs: Vec<FileID>,
+    #[serde(default)]
+    pub(super) compacted_seq: u64,
+}
+
+// None if the data dir has no manifest
+pub(super) fn load_manifest(log_dir_path: &Path) -> Result<Option<Manifest>> {
+    let file = match File::open(log_dir_path.join(MANIFEST_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let manifest = bson::from_document(Document::from_reader(BufReader::new(file))?)?;
+    Ok(Some(manifest))
+}
+
+pub(super) fn manifest_exists(log_dir_path: &Path) -> bool {
+    log_dir_path.join(MANIFEST_FILE).is_file()
+}
+
+// replace the manifest: the new one goes to a temporary file which is renamed over it,
+// and the data dir is synced so that the rename is not reordered with later removals
+pub(super) fn write_manifest(
+    log_dir_path: &Path,
+    logs: Vec<FileID>,
+    compacted_seq: u64,
+) -> Result<()> {
+    let tmp_path = log_dir_path.join(MANIFEST_TMP_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    let mut buf_writer = BufWriter::new(file);
+    let manifest = Manifest {
+        logs,
+        compacted_seq,
+    };
+    buf_writer.write_all(&bson::to_vec(&bson::to_document(&manifest)?)?)?;
+    buf_writer.flush()?;
+    buf_writer.get_ref().sync_all()?;
+
+    fs::rename(tmp_path, log_dir_path.join(MANIFEST_FILE))?;
+    sync_dir(log_dir_path)
+}
+
+// the logs in the data dir in id order, for a store written before there was a manifest,
+// files which are not named like logs are left alone
+pub(super) fn logs_in_dir(log_dir_path: &Path) -> Result<Vec<FileID>> {
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(log_dir_path)? {
+        let file_name = entry?.file_name();
+        if let Some((file_id, LOG_SUFFIX)) = parse_file_name(&file_name.to_string_lossy()) {
+            logs.push(file_id);
+        }
+    }
+    logs.sort_unstable();
+    Ok(logs)
+}
+
+// remove the temporary files and the logs outside of the manifest together with their hints,
+// all of them left behind by a crash
+pub(super) fn remove_leftovers(log_dir_path: &Path, logs: &BTreeSet<FileID>) -> Result<()> {
+    for entry in fs::read_dir(log_dir_path)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let leftover = match parse_file_name(&file_name) {
+            Some((file_id, LOG_SUFFIX | HINT_SUFFIX)) => !logs.contains(&file_id),
+            Some((_, BACKUP_SUFFIX | HINT_TMP_SUFFIX)) => true,
+            _ => file_name == MANIFEST_TMP_FILE,
+        };
+        if leftover {
+            warn!(file = file_name.as_ref(), "remove a leftover file");
+            fs::remove_file(log_dir_path.join(file_name.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+// split `<id>.<suffix>`, None for the files the store does not write
+fn parse_file_name(file_name: &str) -> Option<(FileID, &str)> {
+    let (stem, suffix) = file_name.split_once('.')?;
+    Some((stem.parse().ok()?, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{load_manifest, logs_in_dir, MANIFEST_TMP_FILE};
+    use crate::kvstore::KvStore;
+    use crate::KvsEngine;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // the files a crash may leave next to the logs are removed at open,
+    // the files the store does not write are left alone
+    #[test]
+    fn test_remove_leftovers() {
+        let dir = store_dir("manifest-leftovers");
+        let store = KvStore::open(&dir).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        store.set("b".to_owned(), "2".to_owned()).unwrap();
+        drop(store);
+        thread::sleep(Duration::from_millis(200));
+
+        let logs = load_manifest(&dir).unwrap().unwrap().logs;
+        let unlisted = logs.last().unwrap() + 10;
+        let leftovers = [
+            dir.join(format!("{}.bak", unlisted)),
+            dir.join(MANIFEST_TMP_FILE),
+            dir.join(format!("{}.log", unlisted)),
+        ];
+        for leftover in leftovers.iter() {
+            fs::write(leftover, b"leftover").unwrap();
+        }
+        let stray = dir.join("notes.txt");
+        fs::write(&stray, b"notes").unwrap();
+
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        for leftover in leftovers.iter() {
+            assert!(!leftover.exists(), "{:?} is left", leftover);
+        }
+        assert_eq!(fs::read(&stray).unwrap(), b"notes");
+        assert_eq!(load_manifest(&dir).unwrap().unwrap().logs, logs);
+    }
+
+    #[test]
+    fn test_logs_in_dir() {
+        let dir = store_dir("manifest-logs-in-dir");
+        fs::create_dir_all(&dir).unwrap();
+        for file_name in ["10.log", "2.log", "2.hint", "3.bak", "notes.txt", "x.log"] {
+            fs::write(dir.join(file_name), b"").unwrap();
+        }
+        assert_eq!(logs_in_dir(&dir).unwrap(), vec![2, 10]);
+    }
+}
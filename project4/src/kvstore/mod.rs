@@ -0,0 +1,1837 @@
+mod backup;
+mod cache;
+mod changes;
+mod compaction;
+mod compression;
+mod expiry;
+mod hint;
+mod manifest;
+mod options;
+mod record;
+mod refresh;
+mod segments;
+mod snapshot;
+mod sync;
+
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, ErrorKind, SeekFrom};
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Sender};
+use im::OrdMap;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::kvserror::{KvsError, Result};
+use crate::range::is_empty_range;
+use crate::ttl::{expire_at, now_millis};
+use crate::{BatchOp, EngineStats, KvsEngine, WriteBatch};
+use backup::{backup_logs, restore_logs};
+use cache::ValueCache;
+use changes::WriteSignal;
+use compaction::{retire_log, spawn_compactor, CompactionTask};
+use compression::Compressor;
+use expiry::spawn_expirer;
+use hint::{load_hint, write_hint, HintEntry, SeqRange};
+use manifest::{load_manifest, logs_in_dir, manifest_exists, remove_leftovers, write_manifest};
+use record::{
+    decode_record, encode_record, file_header, read_format, LogFormat, Next, RecordReader,
+    FILE_HEADER_LEN,
+};
+use refresh::{refresh, spawn_refresher};
+use segments::Segments;
+use sync::{spawn_periodic_sync, GroupCommit};
+
+pub use cache::CacheStats;
+pub use changes::KvStoreChanges;
+pub use compression::{Compression, CompressionStats};
+pub use options::{CompactionPolicy, Durability, KvStoreOptions};
+pub use segments::SegmentStats;
+pub use snapshot::KvStoreSnapshot;
+
+// the commands written to the logs, see record.rs for their encoding,
+// keys and values are stored as bson binary in the logs of the bson format
+#[derive(Debug, Serialize, Deserialize)]
+enum Command {
+    Set(
+        #[serde(with = "crate::binary")] Vec<u8>,
+        #[serde(with = "crate::binary")] Vec<u8>,
+    ),
+    Rm(#[serde(with = "crate::binary")] Vec<u8>),
+    // a set whose value expires at the given unix time in milliseconds
+    SetEx(
+        #[serde(with = "crate::binary")] Vec<u8>,
+        #[serde(with = "crate::binary")] Vec<u8>,
+        u64,
+    ),
+    // the commands of a write batch, applied as a whole or not at all
+    Batch(Vec<Command>),
+}
+
+type FileID = u32;
+// a persistent map, which a snapshot shares rather than copies
+type LogIndex = OrdMap<Vec<u8>, ValuePos>;
+
+pub struct KvStore {
+    writer: Arc<RwLock<KvWriter>>,
+    // every handle keeps its own open logs
+    readers: RefCell<LogReaders>,
+    // bumped by the compactor each time it retires logs
+    retire_epoch: Arc<AtomicU64>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    write_signal: Arc<WriteSignal>,
+    compressor: Compressor,
+    // None unless the options give it a capacity
+    cache: Option<Arc<ValueCache>>,
+
+    log_dir_path: PathBuf,
+}
+
+// the logs a handle has opened for reading,
+// dropped altogether once the compactor retires logs
+#[derive(Default)]
+struct LogReaders {
+    readers: HashMap<FileID, (BufReader<File>, LogFormat)>,
+    epoch: u64,
+}
+
+struct KvWriter {
+    // None for a read-only store
+    buf_writer: Option<BufWriter<File>>,
+    // ordered, so that keys can be scanned
+    log_index: LogIndex,
+    // index entries of the active log, written out as its hint once sealed
+    active_hint: Vec<HintEntry>,
+    // keys with a ttl ordered by expiry time,
+    // entries of keys set or removed since are skipped when they come up
+    expiry_queue: BTreeSet<(u64, Vec<u8>)>,
+
+    // sealed logs in time order, the active log is not among them
+    sealed_file_ids: BTreeSet<FileID>,
+    active_file_id: FileID,
+    log_dir_path: PathBuf,
+
+    // every appended command gets the next sequence number
+    written_seq: u64,
+    // the commands up to this sequence number may have been dropped by compaction,
+    // so the changes since an earlier one are lost
+    compacted_seq: u64,
+    // the version given to the keys of the last appended command
+    last_version: u64,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // wakes the change feeds once a command is appended
+    write_signal: Arc<WriteSignal>,
+    compressor: Compressor,
+    segment_size: u64,
+    write_buffer_size: usize,
+
+    // at most one compaction runs in the background at a time
+    compaction_policy: CompactionPolicy,
+    compacting: bool,
+    compaction_sender: Sender<CompactionTask>,
+    // the compactions which ran to the end, and how long the last of them took
+    compactions: u64,
+    last_compaction: Option<Duration>,
+
+    // how many snapshots pin each log
+    pinned_logs: HashMap<FileID, usize>,
+    // compacted logs left in place for the snapshots pinning them
+    retired_file_ids: BTreeSet<FileID>,
+    // the live and total bytes of every log
+    segments: Segments,
+    // the sealed logs in an older format, which compaction rewrites whatever their garbage
+    legacy_file_ids: BTreeSet<FileID>,
+    // the sequence numbers of the commands in every log
+    log_seqs: BTreeMap<FileID, SeqRange>,
+    // the locked LOCK file, held for as long as the store may write, None if read-only
+    #[allow(dead_code)]
+    lock_file: Option<File>,
+}
+
+// the state of the logs recovered by build_index
+struct LogState {
+    log_index: LogIndex,
+    active_hint: Vec<HintEntry>,
+    expiry_queue: BTreeSet<(u64, Vec<u8>)>,
+    sealed_file_ids: BTreeSet<FileID>,
+    active_file_id: FileID,
+    segments: Segments,
+    legacy_file_ids: BTreeSet<FileID>,
+    log_seqs: BTreeMap<FileID, SeqRange>,
+    // the sequence number of the last command in the logs
+    last_seq: u64,
+    compacted_seq: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ValuePos {
+    offset: u64,
+    len: u64,
+    file_id: FileID,
+    expire_at: Option<u64>,
+    // the version of the key, which changes with every write to it
+    version: u64,
+    // the bytes of the record this entry keeps live,
+    // the keys of a batch share its record
+    share: u64,
+}
+
+// the version of a key which does not exist
+const ABSENT_VERSION: u64 = 0;
+// the version of every key recovered when the store is opened
+const RECOVERED_VERSION: u64 = 1;
+
+impl ValuePos {
+    fn new(file_id: FileID, offset: u64, len: u64) -> Self {
+        ValuePos {
+            offset,
+            len,
+            file_id,
+            expire_at: None,
+            version: RECOVERED_VERSION,
+            share: len,
+        }
+    }
+
+    fn shared_by(mut self, keys: u64) -> Self {
+        self.share = self.len / keys.max(1);
+        self
+    }
+
+    fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    fn expires_at(mut self, expire_at: Option<u64>) -> Self {
+        self.expire_at = expire_at;
+        self
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
+}
+
+const LOG_SUFFIX: &str = "log";
+const BACKUP_SUFFIX: &str = "bak";
+const LOCK_FILE: &str = "LOCK";
+// how long a restore waits between looks at whether the running compaction has finished
+const RESTORE_WAIT: Duration = Duration::from_millis(10);
+
+impl KvStore {
+    pub fn open(log_dir_path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(log_dir_path, KvStoreOptions::default())
+    }
+
+    pub fn open_with(log_dir_path: impl AsRef<Path>, options: KvStoreOptions) -> Result<Self> {
+        let log_dir_path = PathBuf::from(log_dir_path.as_ref());
+        prepare_dir(&log_dir_path, &options)?;
+        // only one process writes to a store, a read-only one takes no lock
+        let lock_file = match options.read_only {
+            true => None,
+            false => Some(lock_dir(&log_dir_path)?),
+        };
+        let mut log_state = load_logs(&log_dir_path, options.read_only)?;
+
+        // a bufwriter for the current active log
+        let buf_writer = if options.read_only {
+            None
+        } else {
+            let active_file_id = log_state.active_file_id;
+            let mut logs = log_state.sealed_file_ids.clone();
+            logs.insert(active_file_id);
+            write_manifest(
+                &log_dir_path,
+                logs.iter().copied().collect(),
+                log_state.compacted_seq,
+            )?;
+            remove_leftovers(&log_dir_path, &logs)?;
+            let buf_writer = open_active_log(
+                &log_dir_path,
+                active_file_id,
+                options.write_buffer_size,
+                &mut log_state.segments,
+            )?;
+            if options.durability != Durability::None {
+                sync_dir(&log_dir_path)?;
+            }
+            Some(buf_writer)
+        };
+
+        let (compaction_sender, compaction_receiver) = unbounded();
+        let group_commit = Arc::new(GroupCommit::new());
+        let write_signal = Arc::new(WriteSignal::new(
+            log_state.last_seq.max(log_state.compacted_seq),
+        ));
+        let compressor = Compressor::new(options.compression, options.compression_threshold);
+        let writer = Arc::new(RwLock::new(KvWriter::new(
+            buf_writer,
+            log_state,
+            log_dir_path.clone(),
+            lock_file,
+            &options,
+            group_commit.clone(),
+            write_signal.clone(),
+            compressor.clone(),
+            compaction_sender,
+        )));
+        let retire_epoch = Arc::new(AtomicU64::new(0));
+        let cache = match options.cache_capacity {
+            0 => None,
+            capacity => Some(Arc::new(ValueCache::new(capacity))),
+        };
+        // a read-only store never writes, the most it runs in the background is its refresher
+        if options.read_only {
+            if let Some(interval) = options.refresh_interval {
+                spawn_refresher(
+                    Arc::downgrade(&writer),
+                    retire_epoch.clone(),
+                    cache.clone(),
+                    interval,
+                );
+            }
+        } else {
+            if let Durability::Periodic(interval) = options.durability {
+                spawn_periodic_sync(Arc::downgrade(&writer), group_commit.clone(), interval);
+            }
+            spawn_expirer(Arc::downgrade(&writer));
+            spawn_compactor(
+                Arc::downgrade(&writer),
+                retire_epoch.clone(),
+                log_dir_path.clone(),
+                compressor.clone(),
+                compaction_receiver,
+            );
+            // records are only appended in the current format,
+            // so an active log in an older format is sealed right away, and compacted with the others
+            let mut kv_writer = writer.write().unwrap();
+            if kv_writer
+                .legacy_file_ids
+                .contains(&kv_writer.active_file_id)
+            {
+                let log_len = match kv_writer.buf_writer.as_ref() {
+                    Some(buf_writer) => buf_writer.get_ref().metadata()?.len(),
+                    None => 0,
+                };
+                kv_writer.seal_active_log(log_len)?;
+            }
+        }
+
+        Ok(KvStore {
+            log_dir_path,
+            writer,
+            readers: RefCell::new(LogReaders::default()),
+            retire_epoch,
+            durability: options.durability,
+            group_commit,
+            write_signal,
+            compressor,
+            cache,
+        })
+    }
+
+    // how well the records written since the store was opened compressed
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats()
+    }
+
+    // catch up with the process writing to a store opened read-only,
+    // which otherwise sees the store as it was when opened
+    pub fn refresh(&self) -> Result<()> {
+        refresh(&self.writer, &self.retire_epoch, self.cache.as_deref())
+    }
+
+    // the hits and misses of the value cache, all zero without one
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map_or_else(CacheStats::default, |cache| cache.stats())
+    }
+
+    // the live and total bytes of every log, oldest first
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        let writer = self.writer.read().unwrap();
+        writer.segments.stats(writer.active_file_id)
+    }
+
+    // run a write under the writer lock,
+    // and return once it is as durable as the store is configured for
+    fn write<T>(&self, write: impl FnOnce(&mut KvWriter) -> Result<T>) -> Result<T> {
+        let (result, seq) = {
+            let mut writer = self.writer.write().unwrap();
+            if writer.buf_writer.is_none() {
+                return Err(KvsError::ReadOnly);
+            }
+            let result = write(&mut writer)?;
+            (result, writer.written_seq)
+        };
+        if self.durability == Durability::GroupCommit {
+            self.group_commit.wait_synced(seq, &self.writer)?;
+        }
+        Ok(result)
+    }
+
+    fn read_value(&self, key: &[u8], pos: &ValuePos) -> Result<Vec<u8>> {
+        let mut readers = self.readers.borrow_mut();
+        let epoch = self.retire_epoch.load(Ordering::Acquire);
+        if readers.epoch != epoch {
+            readers.readers.clear();
+            readers.epoch = epoch;
+        }
+        readers.read_value(&self.log_dir_path, key, pos)
+    }
+}
+
+impl LogReaders {
+    fn read_value(&mut self, log_dir_path: &Path, key: &[u8], pos: &ValuePos) -> Result<Vec<u8>> {
+        let (buf_reader, format) = match self.readers.entry(pos.file_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let log_file = OpenOptions::new()
+                    .read(true)
+                    .open(path_from_id(log_dir_path, pos.file_id))?;
+                let mut buf_reader = BufReader::new(log_file);
+                let (format, _) = read_format(&mut buf_reader, pos.file_id)?;
+                entry.insert((buf_reader, format))
+            }
+        };
+        buf_reader.seek(SeekFrom::Start(pos.offset))?;
+        let mut bytes = vec![0; pos.len as usize];
+        buf_reader.read_exact(&mut bytes)?;
+        // a record which holds no value of key is as wrong as one failing its checksum
+        let corruption = || KvsError::Corruption {
+            file_id: pos.file_id,
+            offset: pos.offset,
+        };
+        let (_, command) = decode_record(*format, &bytes)?.ok_or_else(corruption)?;
+        value_of(command, key).ok_or_else(corruption)
+    }
+}
+
+impl Clone for KvStore {
+    // a clone shares the writer but opens the logs on its own
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+            readers: RefCell::new(LogReaders::default()),
+            retire_epoch: self.retire_epoch.clone(),
+            durability: self.durability,
+            group_commit: self.group_commit.clone(),
+            write_signal: self.write_signal.clone(),
+            compressor: self.compressor.clone(),
+            cache: self.cache.clone(),
+            log_dir_path: self.log_dir_path.clone(),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+    type Changes = KvStoreChanges;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let command = Command::Set(key, value);
+        self.write(|writer| writer.append_command(command))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let command = Command::SetEx(key, value, expire_at(ttl));
+        self.write(|writer| writer.append_command(command))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.write(|writer| writer.remove(key))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        self.write(|writer| {
+            let current = self.current_value(writer, &key)?;
+            if current != expected {
+                return Ok(Err(current));
+            }
+            match new {
+                Some(value) => writer.append_command(Command::Set(key, value))?,
+                None if current.is_some() => writer.remove(key)?,
+                None => {}
+            }
+            Ok(Ok(()))
+        })
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.write(|writer| {
+            let current = self.current_value(writer, &key)?;
+            writer.append_command(Command::Set(key, value))?;
+            Ok(current)
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        match batch_command(batch) {
+            Some(command) => self.write(|writer| writer.append_command(command)),
+            None => Ok(()),
+        }
+    }
+
+    // reads under the writer lock, so that the value and its version go together
+    fn get_bytes_with_version(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        let mut writer = self.writer.write().unwrap();
+        let value = self.current_value(&mut writer, &key)?;
+        Ok((value, writer.key_version(&key)))
+    }
+
+    fn commit_transaction(&self, reads: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        self.write(|writer| {
+            let now = now_millis();
+            for (key, version) in reads.iter() {
+                // a key expired by now has changed even if nobody removed it yet
+                writer.expire_key(key, now)?;
+                if writer.key_version(key) != *version {
+                    return Err(KvsError::Conflict);
+                }
+            }
+            match batch_command(batch) {
+                Some(command) => writer.append_command(command),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let positions = self.writer.read().unwrap().scan_pos(range, limit);
+        let mut entries = Vec::with_capacity(positions.len());
+        for (key, pos) in positions {
+            // keys removed since the lookup are left out
+            // scans do not fill the cache, which would push the hot keys out
+            if let Some(value) = self.read_key(&key, pos, false)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let pos = self.writer.read().unwrap().get_pos(&key)?;
+        match pos {
+            Some(pos) => self.read_key(&key, pos, true),
+            None => Ok(None),
+        }
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let writer = self.writer.read().unwrap();
+        let now = now_millis();
+        let live_keys = writer
+            .log_index
+            .values()
+            .filter(|pos| !pos.is_expired(now))
+            .count();
+        // every entry is a key, its buffer and its position, leaving out the tree itself
+        let index_bytes: usize = writer
+            .log_index
+            .keys()
+            .map(|key| key.len() + mem::size_of::<Vec<u8>>() + mem::size_of::<ValuePos>())
+            .sum();
+        let segments = writer.segments.stats(writer.active_file_id);
+        Ok(EngineStats {
+            live_keys: live_keys as u64,
+            disk_bytes: segments.iter().map(|stats| stats.total_bytes).sum(),
+            stale_bytes: segments
+                .iter()
+                .map(|stats| stats.total_bytes.saturating_sub(stats.live_bytes))
+                .sum(),
+            segments: segments.len() as u64,
+            compactions: writer.compactions,
+            last_compaction_ms: writer.last_compaction.map(|d| d.as_millis() as u64),
+            index_bytes: index_bytes as u64,
+            compression_ratio: self.compressor.stats().ratio(),
+        })
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        Ok(KvStoreSnapshot::take(&self.writer, &self.log_dir_path))
+    }
+
+    fn changes_since(&self, seq: u64) -> Result<KvStoreChanges> {
+        KvStoreChanges::new(&self.writer, &self.log_dir_path, seq)
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        Ok(self.writer.read().unwrap().written_seq)
+    }
+
+    fn wait_for_write(&self, seq: u64, timeout: Duration) -> Result<u64> {
+        Ok(self.write_signal.wait_after(seq, timeout))
+    }
+
+    // the backup is the store as of the moment the write lock is taken,
+    // the logs it copies are pinned against compaction until it is done
+    fn backup_to(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if store_exists(dir)? {
+            return Err(KvsError::StoreExists(dir.display().to_string()));
+        }
+        std::fs::create_dir_all(dir)?;
+        let (logs, active_len, compacted_seq) = {
+            let mut writer = self.writer.write().unwrap();
+            if let Some(buf_writer) = writer.buf_writer.as_mut() {
+                buf_writer.flush()?;
+            }
+            let active_len = writer.segments.total_bytes(writer.active_file_id);
+            (writer.pin_logs(), active_len, writer.compacted_seq)
+        };
+        let result = backup_logs(&self.log_dir_path, dir, &logs, active_len, compacted_seq);
+        self.writer.write().unwrap().unpin_logs(&logs)?;
+        result
+    }
+
+    fn restore_from(&self, dir: impl AsRef<Path>) -> Result<()> {
+        loop {
+            let mut writer = self.writer.write().unwrap();
+            if writer.buf_writer.is_none() {
+                return Err(KvsError::ReadOnly);
+            }
+            // a compaction finishing after the restore would swap the old values back in
+            if !writer.compacting {
+                writer.restore(dir.as_ref())?;
+                break;
+            }
+            drop(writer);
+            thread::sleep(RESTORE_WAIT);
+        }
+        // the old logs are gone
+        self.retire_epoch.fetch_add(1, Ordering::Release);
+        if let Some(cache) = self.cache.as_ref() {
+            cache.clear();
+        }
+        Ok(())
+    }
+}
+
+impl KvStore {
+    // the live value of key, for conditional writes holding the writer lock
+    // during which no log that the index points at can be retired
+    fn current_value(&self, writer: &mut KvWriter, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        match writer.get_pos(key)? {
+            Some(pos) if pos.is_expired(now) => {
+                writer.expire_key(key, now)?;
+                Ok(None)
+            }
+            Some(pos) => self.read_cached(key, &pos, true).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // read the value of key found at pos
+    fn read_key(&self, key: &[u8], mut pos: ValuePos, fill_cache: bool) -> Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        if pos.is_expired(now) {
+            // the expiry only needs to be logged, not to be durable
+            self.writer.write().unwrap().expire_key(key, now)?;
+            return Ok(None);
+        }
+        loop {
+            match self.read_cached(key, &pos, fill_cache) {
+                // the log was retired by a compaction after the lookup,
+                // by then the index points at the compacted record,
+                // unless the compaction was done by the writer of a read-only store
+                Err(KvsError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+                    self.refresh()?;
+                    match self.writer.read().unwrap().get_pos(key)? {
+                        Some(new_pos) if new_pos != pos => pos = new_pos,
+                        Some(_) => return Err(KvsError::IoError(e)),
+                        None => return Ok(None),
+                    }
+                }
+                result => return result.map(Some),
+            }
+        }
+    }
+
+    // the value found at pos, from the cache if it holds the value of that version
+    fn read_cached(&self, key: &[u8], pos: &ValuePos, fill_cache: bool) -> Result<Vec<u8>> {
+        let cache = match self.cache.as_ref() {
+            Some(cache) => cache,
+            None => return self.read_value(key, pos),
+        };
+        if let Some(value) = cache.get(key, pos.version) {
+            return Ok(value);
+        }
+        let value = self.read_value(key, pos)?;
+        if fill_cache {
+            cache.insert(key, pos.version, &value);
+        }
+        Ok(value)
+    }
+}
+
+impl KvWriter {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        buf_writer: Option<BufWriter<File>>,
+        log_state: LogState,
+        log_dir_path: PathBuf,
+        lock_file: Option<File>,
+        options: &KvStoreOptions,
+        group_commit: Arc<GroupCommit>,
+        write_signal: Arc<WriteSignal>,
+        compressor: Compressor,
+        compaction_sender: Sender<CompactionTask>,
+    ) -> Self {
+        Self {
+            buf_writer,
+            log_index: log_state.log_index,
+            active_hint: log_state.active_hint,
+            expiry_queue: log_state.expiry_queue,
+            sealed_file_ids: log_state.sealed_file_ids,
+            active_file_id: log_state.active_file_id,
+            log_dir_path,
+            // a compaction may have dropped the commands numbered last
+            written_seq: log_state.last_seq.max(log_state.compacted_seq),
+            compacted_seq: log_state.compacted_seq,
+            last_version: RECOVERED_VERSION,
+            durability: options.durability,
+            group_commit,
+            write_signal,
+            compressor,
+            segment_size: options.segment_size,
+            write_buffer_size: options.write_buffer_size,
+            compaction_policy: options.compaction_policy,
+            compacting: false,
+            compaction_sender,
+            compactions: 0,
+            last_compaction: None,
+            pinned_logs: HashMap::new(),
+            retired_file_ids: BTreeSet::new(),
+            segments: log_state.segments,
+            legacy_file_ids: log_state.legacy_file_ids,
+            log_seqs: log_state.log_seqs,
+            lock_file,
+        }
+    }
+
+    // pick up the logs the writer of the store has written since, for a read-only store
+    fn reload(&mut self) -> Result<()> {
+        let log_state = load_logs(&self.log_dir_path, true)?;
+        self.log_index = log_state.log_index;
+        self.expiry_queue = log_state.expiry_queue;
+        self.sealed_file_ids = log_state.sealed_file_ids;
+        self.active_file_id = log_state.active_file_id;
+        self.segments = log_state.segments;
+        self.legacy_file_ids = log_state.legacy_file_ids;
+        self.log_seqs = log_state.log_seqs;
+        self.written_seq = log_state.last_seq.max(log_state.compacted_seq);
+        self.compacted_seq = log_state.compacted_seq;
+        self.write_signal.notify(self.written_seq);
+        Ok(())
+    }
+
+    fn get_pos(&self, key: &[u8]) -> Result<Option<ValuePos>> {
+        if let Some(pos) = self.log_index.get(key) {
+            Ok(Some(pos.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn scan_pos(&self, range: impl RangeBounds<Vec<u8>>, limit: usize) -> Vec<(Vec<u8>, ValuePos)> {
+        if is_empty_range(&range) {
+            return Vec::new();
+        }
+        let now = now_millis();
+        self.log_index
+            .range((range.start_bound().cloned(), range.end_bound().cloned()))
+            .filter(|(_, pos)| !pos.is_expired(now))
+            .take(limit)
+            .map(|(key, pos)| (key.clone(), pos.clone()))
+            .collect()
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        match self.log_index.get(&key) {
+            Some(pos) if !pos.is_expired(now) => {
+                let command = Command::Rm(key);
+                self.append_command(command)?;
+                Ok(())
+            }
+            _ => {
+                self.expire_key(&key, now)?;
+                Err(KvsError::KeyNotFound(
+                    String::from_utf8_lossy(&key).into_owned(),
+                ))
+            }
+        }
+    }
+
+    fn key_version(&self, key: &[u8]) -> u64 {
+        self.log_index
+            .get(key)
+            .map_or(ABSENT_VERSION, |pos| pos.version)
+    }
+
+    // remove key if its ttl ran out by now,
+    // a read-only store leaves it in place as reads skip expired keys anyway
+    fn expire_key(&mut self, key: &[u8], now: u64) -> Result<()> {
+        if self.buf_writer.is_some()
+            && self
+                .log_index
+                .get(key)
+                .is_some_and(|pos| pos.is_expired(now))
+        {
+            self.append_command(Command::Rm(key.to_vec()))?;
+        }
+        Ok(())
+    }
+
+    // remove the keys whose ttl ran out by now, at most limit of them
+    fn expire_keys(&mut self, now: u64, limit: usize) -> Result<usize> {
+        let mut expired = 0;
+        while expired < limit {
+            match self.expiry_queue.first() {
+                Some((expire_at, _)) if *expire_at <= now => {}
+                _ => break,
+            }
+            let (expire_at, key) = self.expiry_queue.pop_first().unwrap();
+            // the key may have been set again or removed since
+            let pos = self.log_index.get(&key);
+            if pos.is_some_and(|pos| pos.expire_at == Some(expire_at)) {
+                self.append_command(Command::Rm(key))?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+
+    fn append_command(&mut self, command: Command) -> Result<()> {
+        let buf_writer = self.buf_writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let seq = self.written_seq + 1;
+        let bytes = encode_record(seq, &command, &self.compressor)?;
+        buf_writer.write_all(bytes.as_slice())?;
+        buf_writer.flush()?;
+        self.written_seq = seq;
+        self.log_seqs
+            .entry(self.active_file_id)
+            .or_default()
+            .add(seq);
+        self.write_signal.notify(seq);
+        self.last_version += 1;
+        if self.durability == Durability::Sync {
+            buf_writer.get_ref().sync_data()?;
+            self.group_commit.mark_synced(seq);
+        }
+        let current_size = get_current_pos(buf_writer)?;
+        let len = bytes.len() as u64;
+        let offset = current_size - len;
+        if let Command::SetEx(key, _, expire_at) = &command {
+            self.expiry_queue.insert((*expire_at, key.clone()));
+        }
+        self.segments.add_total(self.active_file_id, len);
+        index_command(
+            &mut self.log_index,
+            &mut self.segments,
+            &mut self.active_hint,
+            command,
+            ValuePos::new(self.active_file_id, offset, len).with_version(self.last_version),
+        );
+        if current_size > self.segment_size {
+            self.seal_active_log(current_size)?;
+        }
+        Ok(())
+    }
+
+    // pin every log a snapshot may read from, that is all of them
+    fn pin_logs(&mut self) -> Vec<FileID> {
+        let file_ids: Vec<_> = self
+            .sealed_file_ids
+            .iter()
+            .copied()
+            .chain(Some(self.active_file_id))
+            .collect();
+        for file_id in file_ids.iter() {
+            *self.pinned_logs.entry(*file_id).or_default() += 1;
+        }
+        file_ids
+    }
+
+    // release the logs pinned by a snapshot,
+    // those compacted in the meantime are removed once nothing pins them
+    fn unpin_logs(&mut self, file_ids: &[FileID]) -> Result<()> {
+        for file_id in file_ids {
+            if let Entry::Occupied(mut pins) = self.pinned_logs.entry(*file_id) {
+                *pins.get_mut() -= 1;
+                if *pins.get() == 0 {
+                    pins.remove();
+                    if self.retired_file_ids.remove(file_id) {
+                        retire_log(&self.log_dir_path, *file_id)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // replace every log of the store with those of a backup,
+    // which come after the current logs and are all sealed
+    fn restore(&mut self, backup_dir: &Path) -> Result<()> {
+        let old_logs = self.log_ids();
+        let (mut logs, compacted_seq) =
+            restore_logs(backup_dir, &self.log_dir_path, self.active_file_id + 1)?;
+        let active_file_id = logs.last().copied().unwrap_or(self.active_file_id) + 1;
+        logs.push(active_file_id);
+        let mut log_state = build_index(&self.log_dir_path, &logs, compacted_seq, false)?;
+        // the changes before the restore do not lead up to the restored content,
+        // so the restore takes a sequence number of its own which no feed reads past,
+        // and the commands after it come after those of the backup
+        self.written_seq = self
+            .written_seq
+            .max(log_state.last_seq)
+            .max(log_state.compacted_seq)
+            + 1;
+        self.compacted_seq = self.written_seq;
+        self.write_signal.notify(self.written_seq);
+        write_manifest(&self.log_dir_path, logs, self.compacted_seq)?;
+
+        self.buf_writer = Some(open_active_log(
+            &self.log_dir_path,
+            active_file_id,
+            self.write_buffer_size,
+            &mut log_state.segments,
+        )?);
+        // every key has changed for the transactions which read it before
+        self.last_version += 1;
+        self.log_index = log_state
+            .log_index
+            .iter()
+            .map(|(key, pos)| (key.clone(), pos.clone().with_version(self.last_version)))
+            .collect();
+        self.active_hint = log_state.active_hint;
+        self.expiry_queue = log_state.expiry_queue;
+        self.sealed_file_ids = log_state.sealed_file_ids;
+        self.active_file_id = log_state.active_file_id;
+        self.segments = log_state.segments;
+        self.legacy_file_ids = log_state.legacy_file_ids;
+        self.log_seqs = log_state.log_seqs;
+
+        // the logs pinned by snapshots are removed once released
+        for file_id in old_logs {
+            if self.pinned_logs.contains_key(&file_id) {
+                self.retired_file_ids.insert(file_id);
+            } else {
+                retire_log(&self.log_dir_path, file_id)?;
+            }
+        }
+        sync_dir(&self.log_dir_path)
+    }
+
+    // seal the active log and continue with a fresh one,
+    // the sealed logs are then compacted in the background
+    fn seal_active_log(&mut self, log_len: u64) -> Result<()> {
+        write_hint(
+            &self.log_dir_path,
+            self.active_file_id,
+            log_len,
+            self.log_seqs
+                .get(&self.active_file_id)
+                .copied()
+                .unwrap_or_default(),
+            &self.active_hint,
+        )?;
+        self.active_hint.clear();
+        self.sealed_file_ids.insert(self.active_file_id);
+        // writes never wait on a sealed log, so it is synced right away
+        if self.durability != Durability::None {
+            if let Some(buf_writer) = self.buf_writer.as_ref() {
+                buf_writer.get_ref().sync_data()?;
+            }
+            self.group_commit.mark_synced(self.written_seq);
+        }
+
+        let mut next_file_id = self.active_file_id + 1;
+        let inputs = match self.compacting {
+            true => Vec::new(),
+            false => self.compaction_inputs(),
+        };
+        if !inputs.is_empty() {
+            // the compacted logs take the ids right after the sealed ones,
+            // so that they stay older than every log written from now on
+            let outputs: Vec<_> = (next_file_id..next_file_id + inputs.len() as FileID).collect();
+            let reserved = outputs.len() as FileID;
+            let oldest_retained = self
+                .sealed_file_ids
+                .iter()
+                .copied()
+                .find(|file_id| inputs.binary_search(file_id).is_err());
+            if self
+                .compaction_sender
+                .send(CompactionTask {
+                    inputs,
+                    outputs,
+                    oldest_retained,
+                    segment_size: self.segment_size,
+                })
+                .is_ok()
+            {
+                self.compacting = true;
+                next_file_id += reserved;
+            }
+        }
+
+        // the new active log is named by the manifest before anything is written to it
+        self.active_file_id = next_file_id;
+        write_manifest(&self.log_dir_path, self.log_ids(), self.compacted_seq)?;
+        self.buf_writer = Some(open_active_log(
+            &self.log_dir_path,
+            self.active_file_id,
+            self.write_buffer_size,
+            &mut self.segments,
+        )?);
+        if self.durability != Durability::None {
+            sync_dir(&self.log_dir_path)?;
+        }
+        Ok(())
+    }
+
+    // the logs which make up the store, the active one last
+    fn log_ids(&self) -> Vec<FileID> {
+        let mut logs: Vec<_> = self.sealed_file_ids.iter().copied().collect();
+        logs.push(self.active_file_id);
+        logs
+    }
+
+    // the sealed logs the compaction policy picks, in time order
+    fn compaction_inputs(&self) -> Vec<FileID> {
+        match self.compaction_policy {
+            CompactionPolicy::SealedLogs(logs) if self.sealed_file_ids.len() >= logs => {
+                self.sealed_file_ids.iter().copied().collect()
+            }
+            CompactionPolicy::GarbageRatio(ratio) => self
+                .sealed_file_ids
+                .iter()
+                .copied()
+                .filter(|file_id| {
+                    self.segments.garbage_ratio(*file_id) >= ratio
+                        || self.legacy_file_ids.contains(file_id)
+                })
+                .collect(),
+            CompactionPolicy::Disabled => Vec::new(),
+            // the logs in an older format are upgraded even below the threshold
+            CompactionPolicy::SealedLogs(_) => self.legacy_file_ids.iter().copied().collect(),
+        }
+    }
+}
+
+// point the index at a command appended at pos
+// and note the change in the hint of its log
+fn index_command(
+    log_index: &mut LogIndex,
+    segments: &mut Segments,
+    hint: &mut Vec<HintEntry>,
+    command: Command,
+    pos: ValuePos,
+) {
+    match command {
+        Command::Set(k, _) => {
+            hint.push(HintEntry::Set {
+                key: k.clone(),
+                offset: pos.offset,
+                len: pos.len,
+                expire_at: None,
+            });
+            index_key(log_index, segments, k, pos);
+        }
+        Command::SetEx(k, _, expire_at) => {
+            hint.push(HintEntry::Set {
+                key: k.clone(),
+                offset: pos.offset,
+                len: pos.len,
+                expire_at: Some(expire_at),
+            });
+            index_key(log_index, segments, k, pos.expires_at(Some(expire_at)));
+        }
+        Command::Rm(k) => {
+            unindex_key(log_index, segments, &k);
+            hint.push(HintEntry::Rm(k));
+        }
+        Command::Batch(commands) => {
+            let commands = last_writes(commands);
+            let keys = commands
+                .iter()
+                .filter(|command| matches!(command, Command::Set(..) | Command::SetEx(..)))
+                .count();
+            let pos = pos.shared_by(keys as u64);
+            for command in commands {
+                index_command(log_index, segments, hint, command, pos.clone());
+            }
+        }
+    }
+}
+
+fn index_key(log_index: &mut LogIndex, segments: &mut Segments, key: Vec<u8>, pos: ValuePos) {
+    segments.add_live(&pos);
+    if let Some(old_pos) = log_index.insert(key, pos) {
+        segments.drop_live(&old_pos);
+    }
+}
+
+fn unindex_key(log_index: &mut LogIndex, segments: &mut Segments, key: &[u8]) {
+    if let Some(old_pos) = log_index.remove(key) {
+        segments.drop_live(&old_pos);
+    }
+}
+
+// the commands of a batch which take effect, the last one of every key in batch order,
+// as a batch applies its writes one after the other
+fn last_writes(commands: Vec<Command>) -> Vec<Command> {
+    let mut keys = HashSet::new();
+    let mut last: Vec<_> = commands
+        .into_iter()
+        .rev()
+        .filter(|command| match command {
+            Command::Set(k, _) | Command::SetEx(k, _, _) | Command::Rm(k) => keys.insert(k.clone()),
+            Command::Batch(_) => true,
+        })
+        .collect();
+    last.reverse();
+    last
+}
+
+// the command writing a batch, None for an empty one
+fn batch_command(batch: WriteBatch) -> Option<Command> {
+    if batch.is_empty() {
+        return None;
+    }
+    let commands = batch
+        .into_ops()
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set(key, value) => Command::Set(key, value),
+            BatchOp::Rm(key) => Command::Rm(key),
+        })
+        .collect();
+    Some(Command::Batch(commands))
+}
+
+// the value a command leaves behind for key, if any
+fn value_of(command: Command, key: &[u8]) -> Option<Vec<u8>> {
+    match command {
+        Command::Set(k, value) | Command::SetEx(k, value, _) if k == key => Some(value),
+        Command::Batch(commands) => commands
+            .into_iter()
+            .rev()
+            .find(|command| match command {
+                Command::Set(k, _) | Command::SetEx(k, _, _) | Command::Rm(k) => k == key,
+                Command::Batch(_) => false,
+            })
+            .and_then(|command| value_of(command, key)),
+        _ => None,
+    }
+}
+
+fn get_current_pos<T: Seek>(file: &mut T) -> Result<u64> {
+    let pos = file.stream_position()?;
+    Ok(pos)
+}
+
+// build the log index based on the given logs in time order
+// the last log is the active one, all the others are sealed
+fn build_index(
+    log_dir_path: &Path,
+    logs: &[FileID],
+    compacted_seq: u64,
+    read_only: bool,
+) -> Result<LogState> {
+    let mut log_pointer = LogIndex::new();
+    let mut segments = Segments::default();
+    let mut active_hint = Vec::new();
+    let mut legacy_file_ids = BTreeSet::new();
+    let mut log_seqs = BTreeMap::new();
+    let mut last_seq = 0;
+    let active_index = logs.len().saturating_sub(1);
+    for (i, file_id) in logs.iter().copied().enumerate() {
+        let log_path = &path_from_id(log_dir_path, file_id);
+        let file = match File::open(log_path) {
+            Ok(file) => file,
+            // a crash right after the manifest named a new active log
+            // may leave it uncreated
+            Err(e) if e.kind() == ErrorKind::NotFound && i == active_index => break,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(KvsError::MissingLog(file_id)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let log_len = file.metadata()?.len();
+        let mut records = RecordReader::new(BufReader::new(file), file_id)?;
+        if records.format() != LogFormat::Binary {
+            legacy_file_ids.insert(file_id);
+        }
+
+        // sealed logs are loaded from their hints when possible,
+        // the active log is always replayed
+        if i != active_index {
+            if let Some((entries, seqs)) = load_hint(log_dir_path, file_id, log_len) {
+                last_seq = last_seq.max(seqs.last);
+                log_seqs.insert(file_id, seqs);
+                // the keys set by a batch share its offset
+                let mut keys_at: HashMap<u64, HashSet<&[u8]>> = HashMap::new();
+                for entry in entries.iter() {
+                    if let HintEntry::Set { key, offset, .. } = entry {
+                        keys_at.entry(*offset).or_default().insert(key);
+                    }
+                }
+                let keys_at: HashMap<_, _> = keys_at
+                    .into_iter()
+                    .map(|(offset, keys)| (offset, keys.len() as u64))
+                    .collect();
+                segments.add_total(file_id, log_len);
+                for entry in entries {
+                    match entry {
+                        HintEntry::Set {
+                            key,
+                            offset,
+                            len,
+                            expire_at,
+                        } => {
+                            let pos = ValuePos::new(file_id, offset, len)
+                                .expires_at(expire_at)
+                                .shared_by(keys_at[&offset]);
+                            index_key(&mut log_pointer, &mut segments, key, pos);
+                        }
+                        HintEntry::Rm(key) => unindex_key(&mut log_pointer, &mut segments, &key),
+                    };
+                }
+                continue;
+            }
+        }
+
+        let mut hint = Vec::new();
+        let seqs: &mut SeqRange = log_seqs.entry(file_id).or_default();
+        let mut log_end = records.offset();
+        loop {
+            let (offset, len, command) = match records.next_record()? {
+                Next::Record {
+                    offset,
+                    len,
+                    seq,
+                    command,
+                } => {
+                    last_seq = last_seq.max(seq);
+                    seqs.add(seq);
+                    (offset, len, command)
+                }
+                Next::End => break,
+                // the logs written before records were framed are never truncated,
+                // as their records carry no checksum to tell a torn one for sure;
+                // reading stops there as it always did, and compaction leaves the rest behind
+                Next::Torn { offset } if records.format() == LogFormat::RawBson => {
+                    warn!(file_id, offset, "stop reading a log at a torn bson record");
+                    break;
+                }
+                Next::Corrupt { offset, end }
+                    if records.format() == LogFormat::RawBson && end == log_len =>
+                {
+                    warn!(file_id, offset, "stop reading a log at a torn bson record");
+                    break;
+                }
+                // a write cut short by a crash leaves a torn record at the end of the active log,
+                // which is dropped as that write was never acknowledged
+                // a read-only store just stops reading there
+                Next::Torn { offset } if i == active_index => {
+                    if !read_only {
+                        truncate_log(log_path, offset)?;
+                    }
+                    break;
+                }
+                Next::Corrupt { offset, end } if i == active_index && end == log_len => {
+                    if !read_only {
+                        truncate_log(log_path, offset)?;
+                    }
+                    break;
+                }
+                Next::Torn { offset } | Next::Corrupt { offset, .. } => {
+                    return Err(KvsError::Corruption { file_id, offset })
+                }
+            };
+            log_end = offset + len;
+            index_command(
+                &mut log_pointer,
+                &mut segments,
+                &mut hint,
+                command,
+                ValuePos::new(file_id, offset, len),
+            );
+        }
+        segments.add_total(file_id, log_end);
+        // only the hint of the active log is kept in memory
+        if i == active_index {
+            active_hint = hint;
+        }
+    }
+
+    // keys which expired while the store was closed stay until the expirer logs their removal,
+    // as a compaction dropping their records alone could bring back older values
+    let expiry_queue = log_pointer
+        .iter()
+        .filter_map(|(key, pos)| pos.expire_at.map(|expire_at| (expire_at, key.clone())))
+        .collect();
+
+    let (active_file_id, sealed_file_ids) = match logs.split_last() {
+        Some((active_file_id, sealed_file_ids)) => (*active_file_id, sealed_file_ids),
+        None => (0, &[][..]),
+    };
+    Ok(LogState {
+        log_index: log_pointer,
+        active_hint,
+        expiry_queue,
+        sealed_file_ids: sealed_file_ids.iter().copied().collect(),
+        active_file_id,
+        segments,
+        legacy_file_ids,
+        log_seqs,
+        last_seq,
+        compacted_seq,
+    })
+}
+
+fn truncate_log(log_path: &Path, len: u64) -> Result<()> {
+    warn!(
+        log = log_path.to_string_lossy().as_ref(),
+        len, "truncate the torn tail of the active log"
+    );
+    let file = OpenOptions::new().write(true).open(log_path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn open_active_log(
+    log_dir_path: &Path,
+    file_id: FileID,
+    buffer_size: usize,
+    segments: &mut Segments,
+) -> Result<BufWriter<File>> {
+    let mut active_log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path_from_id(log_dir_path, file_id))?;
+    // a new log starts with its file header
+    if active_log_file.metadata()?.len() == 0 {
+        active_log_file.write_all(&file_header())?;
+        segments.add_total(file_id, FILE_HEADER_LEN);
+    }
+    Ok(BufWriter::with_capacity(buffer_size, active_log_file))
+}
+
+// build the index from the logs named by the manifest,
+// or from every log for a store written before there was one
+fn load_logs(log_dir_path: &Path, read_only: bool) -> Result<LogState> {
+    let mut attempts = 0;
+    loop {
+        let (logs, compacted_seq) = match load_manifest(log_dir_path)? {
+            Some(manifest) => (manifest.logs, manifest.compacted_seq),
+            None => (logs_in_dir(log_dir_path)?, 0),
+        };
+        match build_index(log_dir_path, &logs, compacted_seq, read_only) {
+            // the writer of a read-only store may retire a log right after the manifest is read
+            Err(KvsError::MissingLog(_)) if read_only && attempts < 3 => attempts += 1,
+            result => return result,
+        }
+    }
+}
+
+// lock the data dir for the writer, failing with KvsError::Locked if another one holds it
+fn lock_dir(log_dir_path: &Path) -> Result<File> {
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(log_dir_path.join(LOCK_FILE))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked(log_dir_path.display().to_string())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+// check the data dir against the options, creating it if need be
+fn prepare_dir(log_dir_path: &Path, options: &KvStoreOptions) -> Result<()> {
+    let dir_exists = log_dir_path.is_dir();
+    let store_exists = store_exists(log_dir_path)?;
+    if store_exists && options.error_if_exists {
+        return Err(KvsError::StoreExists(log_dir_path.display().to_string()));
+    }
+    if !store_exists && !options.create_if_missing || !dir_exists && options.read_only {
+        return Err(KvsError::StoreNotFound(log_dir_path.display().to_string()));
+    }
+    if !dir_exists {
+        std::fs::create_dir_all(log_dir_path)?;
+    }
+    Ok(())
+}
+
+// a store exists once the data dir holds a manifest or a log
+fn store_exists(log_dir_path: &Path) -> Result<bool> {
+    Ok(log_dir_path.is_dir()
+        && (manifest_exists(log_dir_path) || !logs_in_dir(log_dir_path)?.is_empty()))
+}
+
+// make the files created in the data dir durable
+fn sync_dir(log_dir_path: &Path) -> Result<()> {
+    File::open(log_dir_path)?.sync_all()?;
+    Ok(())
+}
+
+fn path_from_id(log_dir_path: &Path, file_id: FileID) -> PathBuf {
+    log_dir_path.join(format!("{}.log", file_id))
+}
+
+fn compact_path_from_id(log_dir_path: &Path, file_id: FileID) -> PathBuf {
+    let mut file_path = path_from_id(log_dir_path, file_id);
+    file_path.set_extension(BACKUP_SUFFIX);
+    file_path
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::record::tests::{UNFRAMED_LOG, UNFRAMED_SINGLE_RECORD_LOG};
+    use super::record::FILE_HEADER_LEN;
+    use super::{CompactionPolicy, Compression, KvStore, KvStoreOptions};
+    use crate::{KvsEngine, KvsError, WriteBatch};
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // the store is locked until the compaction which upgrades its logs is over
+    fn reopen(dir: &PathBuf) -> KvStore {
+        thread::sleep(Duration::from_millis(200));
+        KvStore::open(dir).unwrap()
+    }
+
+    // a data dir as the store left it before records were framed
+    fn unframed_store(name: &str, log: &[u8]) -> PathBuf {
+        let dir = store_dir(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("0.log"), log).unwrap();
+        dir
+    }
+
+    // the log written last, which is the active one
+    fn active_log(dir: &PathBuf) -> PathBuf {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .max_by_key(|path| {
+                let stem = path.file_stem().unwrap().to_str().unwrap();
+                stem.parse::<u32>().unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_batch_across_reopen() {
+        let dir = store_dir("batch-reopen");
+        let store = KvStore::open(&dir).unwrap();
+        store.set("c".to_owned(), "0".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").remove("c").set("a", "3");
+        store.write_batch(batch).unwrap();
+        drop(store);
+
+        let store = reopen(&dir);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+    }
+
+    // a batch setting a key twice counts its bytes once per key, whether replayed or
+    // loaded from a hint, and keeps the later value through a compaction
+    #[test]
+    fn test_batch_with_duplicate_keys() {
+        let dir = store_dir("batch-duplicate");
+        let open = |policy| {
+            let options = KvStoreOptions::new()
+                .segment_size(1024)
+                .compaction_policy(policy);
+            KvStore::open_with(&dir, options).unwrap()
+        };
+        let check = |store: &KvStore| {
+            assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+            assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+            // nothing was overwritten, so the first log is live but for its header
+            let first = store.segment_stats()[0];
+            assert!(first.live_bytes + FILE_HEADER_LEN + 1 >= first.total_bytes);
+        };
+        let store = open(CompactionPolicy::Disabled);
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").set("a", "3");
+        store.write_batch(batch).unwrap();
+        // seal the log holding the batch, so that it is indexed from its hint
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        check(&store);
+        let stats = store.segment_stats();
+        drop(store);
+
+        let store = reopen(&dir);
+        check(&store);
+        assert_eq!(store.segment_stats(), stats);
+        drop(store);
+
+        let store = open(CompactionPolicy::SealedLogs(1));
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("w{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        drop(store);
+
+        let store = reopen(&dir);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("3".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+    }
+
+    // a batch cut short by a crash is dropped whole, not replayed in part
+    #[test]
+    fn test_torn_batch() {
+        let dir = store_dir("batch-torn");
+        let store = KvStore::open(&dir).unwrap();
+        store.set("a".to_owned(), "0".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("a", "1").set("b", "2").set("c", "3");
+        store.write_batch(batch).unwrap();
+        drop(store);
+
+        let log_path = active_log(&dir);
+        let log_len = fs::metadata(&log_path).unwrap().len();
+        let log = fs::OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.set_len(log_len - 3).unwrap();
+        drop(log);
+
+        let store = reopen(&dir);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("0".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+        // the torn record is gone, so what is written next is replayed after a reopen
+        store.set("d".to_owned(), "4".to_owned()).unwrap();
+        drop(store);
+        let store = reopen(&dir);
+        assert_eq!(store.get("d".to_owned()).unwrap(), Some("4".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let dir = store_dir("cas");
+        let store = KvStore::open(&dir).unwrap();
+        let some = |value: &str| Some(value.to_owned());
+        // expected None stands for an absent key
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), None, some("1"))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), None, some("2"))
+                .unwrap(),
+            Err(some("1"))
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), some("1"), some("2"))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), some("1"), some("3"))
+                .unwrap(),
+            Err(some("2"))
+        );
+        assert_eq!(store.get("a".to_owned()).unwrap(), some("2"));
+        // new None removes the key
+        store.set("b".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(
+            store
+                .compare_and_swap("b".to_owned(), some("1"), None)
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("b".to_owned(), some("1"), None)
+                .unwrap(),
+            Err(None)
+        );
+        drop(store);
+
+        let store = reopen(&dir);
+        assert_eq!(store.get("a".to_owned()).unwrap(), some("2"));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let dir = store_dir("ttl");
+        let store = KvStore::open(&dir).unwrap();
+        let hour = Duration::from_secs(3600);
+        store
+            .set_with_ttl("a".to_owned(), "1".to_owned(), hour)
+            .unwrap();
+        let short = Duration::from_millis(50);
+        store
+            .set_with_ttl("b".to_owned(), "2".to_owned(), short)
+            .unwrap();
+        store
+            .set_with_ttl("c".to_owned(), "3".to_owned(), short)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        assert_eq!(
+            store.scan_prefix(String::new(), 10).unwrap(),
+            vec![("a".to_owned(), "1".to_owned())]
+        );
+
+        // the expiry times are kept across a reopen, whether the keys expire before or after
+        store
+            .set_with_ttl("d".to_owned(), "4".to_owned(), short)
+            .unwrap();
+        drop(store);
+        thread::sleep(Duration::from_millis(100));
+        let store = reopen(&dir);
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+        assert_eq!(store.get("d".to_owned()).unwrap(), None);
+        assert_eq!(store.scan_prefix(String::new(), 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_expired_keys_compacted() {
+        let dir = store_dir("ttl-compaction");
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(1));
+        let store = KvStore::open_with(&dir, options).unwrap();
+        let short = Duration::from_millis(50);
+        store
+            .set_with_ttl("doomed".to_owned(), "doomed-value".to_owned(), short)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // seal the log holding the key, which is compacted right away
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(store.get("doomed".to_owned()).unwrap(), None);
+        for entry in fs::read_dir(&dir).unwrap() {
+            let bytes = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!bytes.windows(12).any(|window| window == b"doomed-value"));
+        }
+        // nothing but the headers is stale once the expired key is compacted away
+        let stats = store.stats().unwrap();
+        assert!(stats.stale_bytes <= FILE_HEADER_LEN * stats.segments);
+    }
+
+    // the expirer's removal makes the expired value stale
+    #[test]
+    fn test_expired_keys_swept() {
+        let dir = store_dir("ttl-sweep");
+        let options = KvStoreOptions::new().compaction_policy(CompactionPolicy::Disabled);
+        let store = KvStore::open_with(&dir, options).unwrap();
+        let value = "x".repeat(1000);
+        store
+            .set_with_ttl("doomed".to_owned(), value, Duration::from_millis(50))
+            .unwrap();
+        store.set("kept".to_owned(), "kept".to_owned()).unwrap();
+        assert!(store.stats().unwrap().stale_bytes <= FILE_HEADER_LEN);
+        thread::sleep(Duration::from_millis(1500));
+        // swept without being read
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.live_keys, 1);
+        assert!(stats.stale_bytes > 1000);
+        assert_eq!(
+            store.get("kept".to_owned()).unwrap(),
+            Some("kept".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_data_dir_lock() {
+        let dir = store_dir("lock");
+        let store = KvStore::open(&dir).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        assert!(matches!(
+            KvStore::open(&dir).map(|_| ()),
+            Err(KvsError::Locked(_))
+        ));
+
+        // a read-only store opens alongside the writer and follows it once refreshed
+        let options = KvStoreOptions::new().read_only(true);
+        let reader = KvStore::open_with(&dir, options).unwrap();
+        assert_eq!(reader.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        store.set("b".to_owned(), "2".to_owned()).unwrap();
+        reader.refresh().unwrap();
+        assert_eq!(reader.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert!(matches!(
+            reader.set("c".to_owned(), "3".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.remove("a".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+
+        // the lock goes with the writer
+        drop(store);
+        let store = reopen(&dir);
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+    }
+
+    // a handle which read from the logs a compaction replaced reads the current values
+    // from the logs in their place, the retired ones closed
+    #[test]
+    fn test_reads_across_compaction() {
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(1));
+        let store = KvStore::open_with(store_dir("reads-compaction"), options).unwrap();
+        for i in 0..50 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+        let epoch = store.readers.borrow().epoch;
+        for i in 0..50 {
+            store.set(format!("k{}", i), format!("w{}", i)).unwrap();
+        }
+        for i in 0..100 {
+            store.set(format!("x{}", i), format!("y{}", i)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(store.stats().unwrap().compactions > 0);
+
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("k{}", i)).unwrap(),
+                Some(format!("w{}", i))
+            );
+        }
+        let readers = store.readers.borrow();
+        assert!(readers.epoch > epoch);
+        let logs: Vec<_> = store
+            .segment_stats()
+            .iter()
+            .map(|stats| stats.file_id)
+            .collect();
+        assert!(readers.readers.keys().all(|file_id| logs.contains(file_id)));
+    }
+
+    #[test]
+    fn test_open_options() {
+        let dir = store_dir("open-options");
+        let err = KvStore::open_with(&dir, KvStoreOptions::new().create_if_missing(false));
+        assert!(matches!(err, Err(KvsError::StoreNotFound(_))));
+        let err = KvStore::open_with(&dir, KvStoreOptions::new().read_only(true));
+        assert!(matches!(err, Err(KvsError::StoreNotFound(_))));
+        // neither creates the data dir
+        assert!(!dir.exists());
+
+        // an empty data dir holds no store yet
+        fs::create_dir_all(&dir).unwrap();
+        let err = KvStore::open_with(&dir, KvStoreOptions::new().create_if_missing(false));
+        assert!(matches!(err, Err(KvsError::StoreNotFound(_))));
+
+        let store = KvStore::open_with(&dir, KvStoreOptions::new().error_if_exists(true)).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        drop(store);
+        thread::sleep(Duration::from_millis(200));
+        let err = KvStore::open_with(&dir, KvStoreOptions::new().error_if_exists(true));
+        assert!(matches!(err, Err(KvsError::StoreExists(_))));
+        let store =
+            KvStore::open_with(&dir, KvStoreOptions::new().create_if_missing(false)).unwrap();
+        assert_eq!(store.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+    }
+
+    #[test]
+    fn test_open_unframed_logs() {
+        let dir = unframed_store("unframed-single", UNFRAMED_SINGLE_RECORD_LOG);
+        for _ in 0..2 {
+            let store = reopen(&dir);
+            assert_eq!(
+                store.get("key".to_owned()).unwrap(),
+                Some("value".to_owned())
+            );
+        }
+
+        let dir = unframed_store("unframed", UNFRAMED_LOG);
+        for _ in 0..2 {
+            let store = reopen(&dir);
+            assert_eq!(store.get("a".to_owned()).unwrap(), None);
+            assert_eq!(store.get("b".to_owned()).unwrap(), Some("4".to_owned()));
+            assert_eq!(store.get("c".to_owned()).unwrap(), Some("3".to_owned()));
+            store.set("d".to_owned(), "5".to_owned()).unwrap();
+        }
+    }
+
+    // a log in no known format is left as it is
+    #[test]
+    fn test_open_unrecognized_log() {
+        let log = b"neither a header nor a record";
+        let dir = unframed_store("unrecognized", log);
+        assert!(matches!(
+            KvStore::open(&dir).map(|_| ()),
+            Err(KvsError::UnrecognizedLog(0))
+        ));
+        assert_eq!(fs::read(dir.join("0.log")).unwrap(), log);
+    }
+
+    #[test]
+    fn test_compression_ratio_in_stats() {
+        let dir = store_dir("compression-stats");
+        let options = KvStoreOptions::new().compression(Compression::Lz4);
+        let store = KvStore::open_with(&dir, options).unwrap();
+        assert_eq!(store.stats().unwrap().compression_ratio, 1.0);
+        for i in 0..10 {
+            store.set(format!("k{}", i), "abc".repeat(1000)).unwrap();
+        }
+        assert!(store.stats().unwrap().compression_ratio > 2.0);
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use super::Compression;
+
+// how far a write has to get before KvStore acknowledges it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    // fsync the active log on every write
+    Sync,
+    // concurrent writers share one fsync of the active log
+    GroupCommit,
+    // fsync the active log once every interval,
+    // a crash loses the writes acknowledged since the last fsync
+    Periodic(Duration),
+    // hand the write over to the OS only
+    None,
+}
+
+// when the sealed logs are compacted in the background
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionPolicy {
+    // once a log is sealed while at least this many sealed logs have piled up, all of them
+    SealedLogs(usize),
+    // once a log is sealed, the sealed logs whose garbage ratio reached this one
+    GarbageRatio(f64),
+    // never, the logs only ever grow
+    Disabled,
+}
+
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) durability: Durability,
+    pub(super) compression: Compression,
+    pub(super) compression_threshold: usize,
+    pub(super) segment_size: u64,
+    pub(super) compaction_policy: CompactionPolicy,
+    pub(super) write_buffer_size: usize,
+    pub(super) cache_capacity: usize,
+    pub(super) read_only: bool,
+    pub(super) refresh_interval: Option<Duration>,
+    pub(super) create_if_missing: bool,
+    pub(super) error_if_exists: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::None,
+            compression: Compression::None,
+            compression_threshold: 512,
+            segment_size: 1024 * 1024, // 1MB
+            compaction_policy: CompactionPolicy::GarbageRatio(0.5),
+            write_buffer_size: 8 * 1024, // 8KB
+            cache_capacity: 0,
+            read_only: false,
+            refresh_interval: None,
+            create_if_missing: true,
+            error_if_exists: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    // the codec new records are compressed with,
+    // records written with another one are still read
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // records shorter than this many bytes are not worth compressing
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    // the active log is sealed once it grows past this many bytes,
+    // and compaction splits its output into logs of about this size
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    // the buffer size of the writer of the active log
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    // cache up to this many bytes of keys and values read,
+    // 0, the default, disables the cache
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    // open the store for reads only, every write fails with KvsError::ReadOnly
+    // and nothing in the data dir is touched, not even a torn tail of the active log
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    // refresh a read-only store once every interval, as KvStore::refresh does,
+    // a store which writes has nothing to catch up with and ignores it
+    pub fn refresh_interval(mut self, refresh_interval: Duration) -> Self {
+        self.refresh_interval = Some(refresh_interval);
+        self
+    }
+
+    // create the data dir if there is no store yet, the default,
+    // a read-only store is never created
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    // fail with KvsError::StoreExists if the data dir already holds a store
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+}
@@ -0,0 +1,633 @@
+// every log starts with a file header:
+//
+// | magic: "KVSL" | format: u32 |
+//
+// followed by its commands, each framed as a record:
+//
+// | len: u32 | crc: u32 | codec: u8 | body: len bytes |
+//
+// integers are little endian, the body is the sequence number of the command followed by
+// the encoded command, compressed together with the codec (see compression.rs),
+// and the crc covers the len field, the codec and the body.
+// sequence numbers go up by one with every command appended to the store, starting from 1.
+// a command is a tag followed by its fields, keys and values prefixed by their u32 length:
+//
+// | 1 | key | value |                     set
+// | 2 | key |                             rm
+// | 3 | key | value | expire_at: u64 |    set with a ttl
+// | 4 | count: u32 | commands |           batch, none of its commands being a batch
+//
+// logs of format 2 have no sequence numbers in their bodies,
+// and logs written before there was a file header hold bson encoded commands instead,
+// framed as | len: u32 | crc: u32 | payload: len bytes |, see decode_payload,
+// or, written before records were framed, bson documents back to back with nothing in between.
+// they are still read, their commands numbered 0, and compaction rewrites them in the current format
+
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use super::compression::{decode_payload, decompress, Compression, Compressor};
+use super::{Command, FileID};
+use crate::kvserror::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSL";
+const FORMAT_VERSION: u32 = 3;
+const UNSEQUENCED_FORMAT_VERSION: u32 = 2;
+pub(super) const FILE_HEADER_LEN: u64 = 8;
+const RECORD_HEADER_LEN: u64 = 8;
+
+const SET_TAG: u8 = 1;
+const RM_TAG: u8 = 2;
+const SET_EX_TAG: u8 = 3;
+const BATCH_TAG: u8 = 4;
+
+// how the records of a log are encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum LogFormat {
+    // bson documents with no framing, in the logs written before records had a checksum
+    RawBson,
+    // bson commands, in the logs without a file header
+    Bson,
+    // format 2, binary commands without sequence numbers
+    Unsequenced,
+    Binary,
+}
+
+impl LogFormat {
+    // the bytes at the front of a record which tell its length
+    fn prefix_len(self) -> u64 {
+        match self {
+            // the length of a bson document
+            LogFormat::RawBson => 4,
+            _ => RECORD_HEADER_LEN,
+        }
+    }
+
+    // the length of a whole record from the length field at its front
+    fn record_len(self, len: u64) -> u64 {
+        match self {
+            // which counts itself
+            LogFormat::RawBson => len,
+            LogFormat::Bson => RECORD_HEADER_LEN + len,
+            LogFormat::Unsequenced | LogFormat::Binary => RECORD_HEADER_LEN + 1 + len,
+        }
+    }
+}
+
+pub(super) fn file_header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// read the file header of a log, returns its format and where its records start.
+// an empty log or one whose header is torn is taken for a log of the current format
+// which is empty or whose first record is torn,
+// while a log with no header must start with a valid record of an older format
+// or it fails with KvsError::UnrecognizedLog, as it may be anything but a torn log
+pub(super) fn read_format<R: Read + Seek>(
+    reader: &mut R,
+    file_id: FileID,
+) -> Result<(LogFormat, u64)> {
+    let log_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    let (format, start) = if read == header.len() && &header[..4] == MAGIC {
+        match u32::from_le_bytes(header[4..].try_into().unwrap()) {
+            FORMAT_VERSION => (LogFormat::Binary, FILE_HEADER_LEN),
+            UNSEQUENCED_FORMAT_VERSION => (LogFormat::Unsequenced, FILE_HEADER_LEN),
+            format => return Err(KvsError::UnsupportedFormat(format)),
+        }
+    } else if read < header.len() && header[..read] == file_header()[..read] {
+        (LogFormat::Binary, 0)
+    } else {
+        match headerless_format(reader, log_len)? {
+            Some(format) => (format, 0),
+            None => return Err(KvsError::UnrecognizedLog(file_id)),
+        }
+    };
+    reader.seek(SeekFrom::Start(start))?;
+    Ok((format, start))
+}
+
+// tell the formats of the logs without a file header apart by their first record
+fn headerless_format<R: Read + Seek>(reader: &mut R, log_len: u64) -> Result<Option<LogFormat>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut len = [0; 4];
+    read_full(reader, &mut len)?;
+    let len = u32::from_le_bytes(len) as u64;
+    if len > log_len {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    let mut first = vec![0; (RECORD_HEADER_LEN + len).min(log_len) as usize];
+    read_full(reader, &mut first)?;
+    for format in [LogFormat::Bson, LogFormat::RawBson] {
+        let record_len = format.record_len(len);
+        if record_len <= log_len && decode_record(format, &first[..record_len as usize])?.is_some()
+        {
+            return Ok(Some(format));
+        }
+    }
+    Ok(None)
+}
+
+pub(super) fn encode_record(
+    seq: u64,
+    command: &Command,
+    compressor: &Compressor,
+) -> Result<Vec<u8>> {
+    let mut body = seq.to_le_bytes().to_vec();
+    encode_command(command, &mut body);
+    let (codec, body) = compressor.compress(body)?;
+    let len = (body.len() as u32).to_le_bytes();
+    let codec = [codec.codec_byte()];
+
+    let mut bytes = Vec::with_capacity((RECORD_HEADER_LEN + 1) as usize + body.len());
+    bytes.extend_from_slice(&len);
+    bytes.extend_from_slice(&checksum(&len, &[&codec, &body]).to_le_bytes());
+    bytes.extend_from_slice(&codec);
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+// decode a whole record of a log in format into its sequence number and command,
+// returns None if it fails its checksum
+pub(super) fn decode_record(format: LogFormat, bytes: &[u8]) -> Result<Option<(u64, Command)>> {
+    if (bytes.len() as u64) < format.prefix_len() {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as u64;
+    if format.record_len(len) != bytes.len() as u64 {
+        return Ok(None);
+    }
+    // a bson document carries no checksum, the document itself has to be a valid command
+    if format == LogFormat::RawBson {
+        return Ok(decode_payload(bytes).map(|command| (0, command)));
+    }
+    let (header, payload) = bytes.split_at(RECORD_HEADER_LEN as usize);
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    if checksum(&header[..4], &[payload]) != crc {
+        return Ok(None);
+    }
+    if format == LogFormat::Bson {
+        return Ok(decode_payload(payload).map(|command| (0, command)));
+    }
+    let body = match Compression::from_codec_byte(payload[0])
+        .and_then(|codec| decompress(codec, &payload[1..]))
+    {
+        Some(body) => body,
+        None => return Ok(None),
+    };
+    let mut body = body.as_slice();
+    let seq = match format {
+        LogFormat::Binary => match take(&mut body, 8) {
+            Some(seq) => u64::from_le_bytes(seq.try_into().unwrap()),
+            None => return Ok(None),
+        },
+        _ => 0,
+    };
+    Ok(decode_command(&mut body, true).map(|command| (seq, command)))
+}
+
+fn encode_command(command: &Command, bytes: &mut Vec<u8>) {
+    match command {
+        Command::Set(key, value) => {
+            bytes.push(SET_TAG);
+            encode_bytes(key, bytes);
+            encode_bytes(value, bytes);
+        }
+        Command::Rm(key) => {
+            bytes.push(RM_TAG);
+            encode_bytes(key, bytes);
+        }
+        Command::SetEx(key, value, expire_at) => {
+            bytes.push(SET_EX_TAG);
+            encode_bytes(key, bytes);
+            encode_bytes(value, bytes);
+            bytes.extend_from_slice(&expire_at.to_le_bytes());
+        }
+        Command::Batch(commands) => {
+            bytes.push(BATCH_TAG);
+            bytes.extend_from_slice(&(commands.len() as u32).to_le_bytes());
+            for command in commands {
+                encode_command(command, bytes);
+            }
+        }
+    }
+}
+
+fn encode_bytes(field: &[u8], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(field);
+}
+
+// decode a command off the front of body, which holds nothing else at the top level
+fn decode_command(body: &mut &[u8], top_level: bool) -> Option<Command> {
+    let command = match take(body, 1)?[0] {
+        SET_TAG => Command::Set(take_bytes(body)?, take_bytes(body)?),
+        RM_TAG => Command::Rm(take_bytes(body)?),
+        SET_EX_TAG => Command::SetEx(
+            take_bytes(body)?,
+            take_bytes(body)?,
+            u64::from_le_bytes(take(body, 8)?.try_into().unwrap()),
+        ),
+        BATCH_TAG if top_level => {
+            let count = take_u32(body)?;
+            let commands = (0..count)
+                .map(|_| decode_command(body, false))
+                .collect::<Option<_>>()?;
+            Command::Batch(commands)
+        }
+        _ => return None,
+    };
+    match top_level && !body.is_empty() {
+        true => None,
+        false => Some(command),
+    }
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if body.len() < len {
+        return None;
+    }
+    let (field, rest) = body.split_at(len);
+    *body = rest;
+    Some(field)
+}
+
+fn take_u32(body: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(body, 4)?.try_into().unwrap()))
+}
+
+fn take_bytes(body: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = take_u32(body)? as usize;
+    take(body, len).map(<[u8]>::to_vec)
+}
+
+// what reading the next record of a log runs into
+pub(super) enum Next {
+    Record {
+        offset: u64,
+        len: u64,
+        seq: u64,
+        command: Command,
+    },
+    // the log ends right before offset
+    End,
+    // the log ends in the middle of the record at offset
+    Torn {
+        offset: u64,
+    },
+    // the record at offset spans up to end but fails its checksum
+    Corrupt {
+        offset: u64,
+        end: u64,
+    },
+}
+
+pub(super) struct RecordReader<R> {
+    reader: R,
+    format: LogFormat,
+    offset: u64,
+    // the length of the log when last looked at, which the active log outgrows
+    log_len: u64,
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    // start reading the records of a log right after its file header
+    pub(super) fn new(mut reader: R, file_id: FileID) -> Result<Self> {
+        let (format, offset) = read_format(&mut reader, file_id)?;
+        let log_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            reader,
+            format,
+            offset,
+            log_len,
+        })
+    }
+
+    pub(super) fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    // where the next record starts, the end of the log once it is read through
+    pub(super) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // go back to the record at offset, to read it again once the log has grown
+    pub(super) fn seek(&mut self, offset: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        Ok(())
+    }
+
+    // the reader stops at the first record which is not a valid one
+    pub(super) fn next_record(&mut self) -> Result<Next> {
+        let offset = self.offset;
+        let prefix_len = self.format.prefix_len();
+        let mut prefix = vec![0; prefix_len as usize];
+        match read_full(&mut self.reader, &mut prefix)? {
+            0 => return Ok(Next::End),
+            n if n < prefix.len() => return Ok(Next::Torn { offset }),
+            _ => {}
+        }
+        let len = u32::from_le_bytes(prefix[..4].try_into().unwrap()) as u64;
+        let len = self.format.record_len(len);
+        if len < prefix_len {
+            return Ok(Next::Corrupt {
+                offset,
+                end: offset + prefix_len,
+            });
+        }
+        // the length is checked against the log before it is trusted with an allocation
+        if offset + len > self.log_len {
+            self.log_len = self.reader.seek(SeekFrom::End(0))?;
+            self.reader.seek(SeekFrom::Start(offset + prefix_len))?;
+            if offset + len > self.log_len {
+                return Ok(Next::Torn { offset });
+            }
+        }
+        let mut bytes = prefix;
+        bytes.resize(len as usize, 0);
+        if read_full(&mut self.reader, &mut bytes[prefix_len as usize..])?
+            < (len - prefix_len) as usize
+        {
+            return Ok(Next::Torn { offset });
+        }
+
+        let end = offset + len;
+        match decode_record(self.format, &bytes)? {
+            Some((seq, command)) => {
+                self.offset = end;
+                Ok(Next::Record {
+                    offset,
+                    len,
+                    seq,
+                    command,
+                })
+            }
+            None => Ok(Next::Corrupt { offset, end }),
+        }
+    }
+}
+
+// read until buf is full or the reader runs out,
+// returns how many bytes were read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+fn checksum(len: &[u8], payload: &[&[u8]]) -> u32 {
+    !payload
+        .iter()
+        .fold(crc32_update(!0, len), |crc, bytes| crc32_update(crc, bytes))
+}
+
+// crc-32 (ieee), the one used by zlib and png
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::io::Cursor;
+
+    use super::{checksum, encode_record, file_header, LogFormat, Next, RecordReader};
+    use crate::kvstore::compression::{Compression, Compressor};
+    use crate::kvstore::Command;
+    use crate::KvsError;
+
+    // 0.log as the store wrote it before records were framed, after
+    // set a 1, set b 2, rm a, set c 3, set b 4
+    pub(in crate::kvstore) const UNFRAMED_LOG: &[u8] = &[
+        0x21, 0x00, 0x00, 0x00, 0x04, 0x53, 0x65, 0x74, 0x00, 0x17, 0x00, 0x00, 0x00, 0x02, 0x30,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x61, 0x00, 0x02, 0x31, 0x00, 0x02, 0x00, 0x00, 0x00, 0x31,
+        0x00, 0x00, 0x00, 0x21, 0x00, 0x00, 0x00, 0x04, 0x53, 0x65, 0x74, 0x00, 0x17, 0x00, 0x00,
+        0x00, 0x02, 0x30, 0x00, 0x02, 0x00, 0x00, 0x00, 0x62, 0x00, 0x02, 0x31, 0x00, 0x02, 0x00,
+        0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x02, 0x52, 0x6d, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x61, 0x00, 0x00, 0x21, 0x00, 0x00, 0x00, 0x04, 0x53, 0x65, 0x74, 0x00,
+        0x17, 0x00, 0x00, 0x00, 0x02, 0x30, 0x00, 0x02, 0x00, 0x00, 0x00, 0x63, 0x00, 0x02, 0x31,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00, 0x21, 0x00, 0x00, 0x00, 0x04, 0x53,
+        0x65, 0x74, 0x00, 0x17, 0x00, 0x00, 0x00, 0x02, 0x30, 0x00, 0x02, 0x00, 0x00, 0x00, 0x62,
+        0x00, 0x02, 0x31, 0x00, 0x02, 0x00, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00,
+    ];
+    // and after set key value alone
+    pub(in crate::kvstore) const UNFRAMED_SINGLE_RECORD_LOG: &[u8] = &[
+        0x27, 0x00, 0x00, 0x00, 0x04, 0x53, 0x65, 0x74, 0x00, 0x1d, 0x00, 0x00, 0x00, 0x02, 0x30,
+        0x00, 0x04, 0x00, 0x00, 0x00, 0x6b, 0x65, 0x79, 0x00, 0x02, 0x31, 0x00, 0x06, 0x00, 0x00,
+        0x00, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x00, 0x00, 0x00,
+    ];
+
+    fn next(reader: &mut RecordReader<Cursor<&[u8]>>) -> Next {
+        reader.next_record().expect("fail to read record")
+    }
+
+    fn reader(log: &[u8]) -> RecordReader<Cursor<&[u8]>> {
+        RecordReader::new(Cursor::new(log), 0).expect("fail to read file header")
+    }
+
+    #[test]
+    fn test_torn_and_corrupt_records() {
+        let compressor = Compressor::default();
+        let first =
+            encode_record(1, &Command::Set(b"a".to_vec(), vec![0xff, 0]), &compressor).unwrap();
+        let second = encode_record(2, &Command::Rm(b"a".to_vec()), &compressor).unwrap();
+        let header = file_header().to_vec();
+        let mut log = [header.clone(), first.clone(), second.clone()].concat();
+
+        let mut records = reader(&log);
+        assert_eq!(records.format(), LogFormat::Binary);
+        assert!(matches!(
+            next(&mut records),
+            Next::Record {
+                offset: 8,
+                seq: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            next(&mut records),
+            Next::Record {
+                seq: 2,
+                command: Command::Rm(_),
+                ..
+            }
+        ));
+        assert!(matches!(next(&mut records), Next::End));
+
+        let torn = &log[..log.len() - 1];
+        let mut records = reader(torn);
+        next(&mut records);
+        let first_end = (header.len() + first.len()) as u64;
+        assert!(matches!(next(&mut records), Next::Torn { offset } if offset == first_end));
+
+        // a log whose file header is torn
+        let mut records = reader(&header[..5]);
+        assert!(matches!(next(&mut records), Next::Torn { offset: 0 }));
+
+        *log.last_mut().unwrap() ^= 0xff;
+        let mut records = reader(&log);
+        next(&mut records);
+        assert!(matches!(next(&mut records), Next::Corrupt { end, .. } if end == log.len() as u64));
+    }
+
+    #[test]
+    fn test_commands() {
+        let compressor = Compressor::new(Compression::Lz4, 16);
+        let commands = vec![
+            Command::Set(b"a".to_vec(), Vec::new()),
+            Command::SetEx(b"b".to_vec(), b"x".repeat(100), u64::MAX),
+            Command::Batch(vec![
+                Command::Rm(b"a".to_vec()),
+                Command::Set(b"c".to_vec(), b"y".repeat(100)),
+            ]),
+        ];
+        let mut log = file_header().to_vec();
+        for (seq, command) in (1..).zip(commands.iter()) {
+            log.extend(encode_record(seq, command, &compressor).unwrap());
+        }
+        let mut records = reader(&log);
+        for (seq, command) in (1..).zip(commands) {
+            match next(&mut records) {
+                Next::Record {
+                    seq: read_seq,
+                    command: read,
+                    ..
+                } => {
+                    assert_eq!(read_seq, seq);
+                    assert_eq!(format!("{:?}", read), format!("{:?}", command))
+                }
+                _ => panic!("fail to read {:?}", command),
+            }
+        }
+        assert!(matches!(next(&mut records), Next::End));
+    }
+
+    // the logs written before there was a file header
+    #[test]
+    fn test_bson_records() {
+        let mut log = Vec::new();
+        for command in [
+            Command::Set(b"a".to_vec(), b"1".to_vec()),
+            Command::Rm(b"a".to_vec()),
+        ] {
+            let payload = bson::to_vec(&bson::to_document(&command).unwrap()).unwrap();
+            let len = (payload.len() as u32).to_le_bytes();
+            log.extend_from_slice(&len);
+            log.extend_from_slice(&checksum(&len, &[&payload]).to_le_bytes());
+            log.extend_from_slice(&payload);
+        }
+        let mut records = reader(&log);
+        assert_eq!(records.format(), LogFormat::Bson);
+        assert!(matches!(
+            next(&mut records),
+            Next::Record {
+                offset: 0,
+                command: Command::Set(..),
+                ..
+            }
+        ));
+        assert!(matches!(
+            next(&mut records),
+            Next::Record {
+                command: Command::Rm(_),
+                ..
+            }
+        ));
+        assert!(matches!(next(&mut records), Next::End));
+    }
+
+    // the logs written before records were framed
+    #[test]
+    fn test_unframed_records() {
+        let mut records = reader(UNFRAMED_LOG);
+        assert_eq!(records.format(), LogFormat::RawBson);
+        let mut commands = Vec::new();
+        while let Next::Record { seq, command, .. } = next(&mut records) {
+            assert_eq!(seq, 0);
+            commands.push(format!("{:?}", command));
+        }
+        assert_eq!(records.offset(), UNFRAMED_LOG.len() as u64);
+        let set = |key: &[u8], value: &[u8]| Command::Set(key.to_vec(), value.to_vec());
+        let expected = [
+            set(b"a", b"1"),
+            set(b"b", b"2"),
+            Command::Rm(b"a".to_vec()),
+            set(b"c", b"3"),
+            set(b"b", b"4"),
+        ];
+        assert_eq!(commands, expected.map(|command| format!("{:?}", command)));
+
+        let mut records = reader(UNFRAMED_SINGLE_RECORD_LOG);
+        assert_eq!(records.format(), LogFormat::RawBson);
+        assert!(matches!(next(&mut records), Next::Record { .. }));
+        assert!(matches!(next(&mut records), Next::End));
+
+        // a torn document is told apart from the end
+        let torn = &UNFRAMED_LOG[..UNFRAMED_LOG.len() - 3];
+        let mut records = reader(torn);
+        for _ in 0..4 {
+            assert!(matches!(next(&mut records), Next::Record { .. }));
+        }
+        assert!(matches!(next(&mut records), Next::Torn { offset } if offset == 0x72));
+    }
+
+    #[test]
+    fn test_unrecognized_logs() {
+        let garbage = b"neither a header nor a record".to_vec();
+        for log in [&garbage[..], &UNFRAMED_SINGLE_RECORD_LOG[..20]] {
+            assert!(matches!(
+                RecordReader::new(Cursor::new(log), 7).map(|_| ()),
+                Err(KvsError::UnrecognizedLog(7))
+            ));
+        }
+    }
+
+    // a corrupt length is not trusted with an allocation of its size
+    #[test]
+    fn test_oversized_length() {
+        let compressor = Compressor::default();
+        let record = encode_record(1, &Command::Rm(b"a".to_vec()), &compressor).unwrap();
+        let mut log = [file_header().to_vec(), record].concat();
+        log[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut records = reader(&log);
+        assert!(matches!(next(&mut records), Next::Torn { offset: 8 }));
+    }
+}
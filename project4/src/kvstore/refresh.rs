@@ -0,0 +1,108 @@
+// a store opened read-only sees the store as it was when opened until it is refreshed,
+// by hand or once every interval from a background thread
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use tracing::error;
+
+use super::cache::ValueCache;
+use super::KvWriter;
+use crate::kvserror::Result;
+
+// catch up with the process writing to the store, nothing to do for that process itself
+pub(super) fn refresh(
+    writer: &RwLock<KvWriter>,
+    retire_epoch: &AtomicU64,
+    cache: Option<&ValueCache>,
+) -> Result<()> {
+    let mut writer = writer.write().unwrap();
+    if writer.buf_writer.is_some() {
+        return Ok(());
+    }
+    writer.reload()?;
+    // the logs compacted by the writer in the meantime are gone,
+    // and the versions of the keys start over
+    retire_epoch.fetch_add(1, Ordering::Release);
+    if let Some(cache) = cache {
+        cache.clear();
+    }
+    Ok(())
+}
+
+// the refresher stops once every handle of the store is dropped
+pub(super) fn spawn_refresher(
+    writer: Weak<RwLock<KvWriter>>,
+    retire_epoch: Arc<AtomicU64>,
+    cache: Option<Arc<ValueCache>>,
+    interval: Duration,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        if let Err(e) = refresh(&writer, &retire_epoch, cache.as_deref()) {
+            error!(error = e.to_string().as_str(), "fail to refresh the store");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kvstore::{CompactionPolicy, KvStore, KvStoreOptions};
+    use crate::KvsEngine;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // a read-only store with a refresh interval follows the writer without being refreshed,
+    // through the compactions of the writer too
+    #[test]
+    fn test_refresh_interval() {
+        let dir = store_dir("refresh-interval");
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(1));
+        let writer = KvStore::open_with(&dir, options).unwrap();
+        writer.set("a".to_owned(), "1".to_owned()).unwrap();
+        let options = KvStoreOptions::new()
+            .read_only(true)
+            .refresh_interval(Duration::from_millis(50))
+            .cache_capacity(1024);
+        let reader = KvStore::open_with(&dir, options).unwrap();
+        assert_eq!(reader.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+
+        writer.set("a".to_owned(), "2".to_owned()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(reader.get("a".to_owned()).unwrap(), Some("2".to_owned()));
+
+        for round in 0..3 {
+            for i in 0..50 {
+                writer
+                    .set(format!("k{}", i), format!("v{}-{}", i, round))
+                    .unwrap();
+            }
+        }
+        writer.remove("a".to_owned()).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(writer.stats().unwrap().compactions > 0);
+        assert_eq!(reader.get("a".to_owned()).unwrap(), None);
+        for i in 0..50 {
+            assert_eq!(
+                reader.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}-2", i))
+            );
+        }
+    }
+}
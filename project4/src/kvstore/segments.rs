@@ -0,0 +1,116 @@
+// how many bytes of every log are live, that is still pointed at by the index,
+// kept up to date as sets and removes supersede records,
+// so that compaction can pick the logs which are mostly garbage.
+// removals never count as live, those which compaction keeps as tombstones included
+
+use std::collections::BTreeMap;
+
+use super::{FileID, ValuePos};
+
+// the usage of one log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub file_id: u32,
+    pub active: bool,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+}
+
+impl SegmentStats {
+    // the share of the log which compaction would drop
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.live_bytes.min(self.total_bytes) as f64 / self.total_bytes as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    total_bytes: u64,
+    live_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Segments {
+    usage: BTreeMap<FileID, Usage>,
+}
+
+impl Segments {
+    pub(super) fn add_total(&mut self, file_id: FileID, bytes: u64) {
+        self.usage.entry(file_id).or_default().total_bytes += bytes;
+    }
+
+    pub(super) fn add_live(&mut self, pos: &ValuePos) {
+        self.usage.entry(pos.file_id).or_default().live_bytes += pos.share;
+    }
+
+    // the record at pos has been superseded
+    pub(super) fn drop_live(&mut self, pos: &ValuePos) {
+        if let Some(usage) = self.usage.get_mut(&pos.file_id) {
+            usage.live_bytes = usage.live_bytes.saturating_sub(pos.share);
+        }
+    }
+
+    pub(super) fn remove(&mut self, file_id: FileID) {
+        self.usage.remove(&file_id);
+    }
+
+    // the bytes of a log which the index has seen, complete records only
+    pub(super) fn total_bytes(&self, file_id: FileID) -> u64 {
+        self.stats_of(file_id, false).total_bytes
+    }
+
+    pub(super) fn garbage_ratio(&self, file_id: FileID) -> f64 {
+        self.stats_of(file_id, false).garbage_ratio()
+    }
+
+    pub(super) fn stats(&self, active_file_id: FileID) -> Vec<SegmentStats> {
+        self.usage
+            .keys()
+            .map(|file_id| self.stats_of(*file_id, *file_id == active_file_id))
+            .collect()
+    }
+
+    fn stats_of(&self, file_id: FileID, active: bool) -> SegmentStats {
+        let usage = self.usage.get(&file_id).copied().unwrap_or_default();
+        SegmentStats {
+            file_id,
+            active,
+            total_bytes: usage.total_bytes,
+            live_bytes: usage.live_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Segments;
+    use crate::kvstore::ValuePos;
+
+    #[test]
+    fn test_garbage_ratio() {
+        let mut segments = Segments::default();
+        // a batch of four keys in a record of 400 bytes, then a lone set of 100 bytes
+        let batch = ValuePos::new(0, 0, 400).shared_by(4);
+        let single = ValuePos::new(0, 400, 100);
+        segments.add_total(0, 500);
+        for _ in 0..4 {
+            segments.add_live(&batch);
+        }
+        segments.add_live(&single);
+        assert_eq!(segments.garbage_ratio(0), 0.0);
+
+        segments.drop_live(&batch);
+        segments.drop_live(&single);
+        assert_eq!(segments.garbage_ratio(0), 0.4);
+
+        let stats = segments.stats(0);
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].active);
+        assert_eq!(stats[0].live_bytes, 300);
+        segments.remove(0);
+        assert!(segments.stats(0).is_empty());
+    }
+}
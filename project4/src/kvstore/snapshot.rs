@@ -0,0 +1,192 @@
+// a snapshot reads the store as of the moment it was taken:
+// it keeps the index as it was, sharing all of it with the store until the store writes on,
+// and pins the logs that index points at,
+// so that compaction leaves them in place until the snapshot is dropped
+
+use std::cell::RefCell;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use tracing::error;
+
+use super::{FileID, KvWriter, LogIndex, LogReaders, ValuePos};
+use crate::kvserror::Result;
+use crate::range::is_empty_range;
+use crate::ttl::now_millis;
+use crate::KvsSnapshot;
+
+pub struct KvStoreSnapshot {
+    log_index: LogIndex,
+    // keys are live or expired as of this time
+    taken_at: u64,
+    pinned_file_ids: Vec<FileID>,
+    readers: RefCell<LogReaders>,
+    writer: Arc<RwLock<KvWriter>>,
+    log_dir_path: PathBuf,
+}
+
+impl KvStoreSnapshot {
+    pub(super) fn take(writer: &Arc<RwLock<KvWriter>>, log_dir_path: &Path) -> Self {
+        let (log_index, pinned_file_ids) = {
+            let mut writer = writer.write().unwrap();
+            (writer.log_index.clone(), writer.pin_logs())
+        };
+        Self {
+            log_index,
+            taken_at: now_millis(),
+            pinned_file_ids,
+            readers: RefCell::new(LogReaders::default()),
+            writer: writer.clone(),
+            log_dir_path: log_dir_path.to_owned(),
+        }
+    }
+
+    fn live_pos(&self, pos: &ValuePos) -> bool {
+        !pos.is_expired(self.taken_at)
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.log_index.get(&key) {
+            Some(pos) if self.live_pos(pos) => self
+                .readers
+                .borrow_mut()
+                .read_value(&self.log_dir_path, &key, pos)
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let mut readers = self.readers.borrow_mut();
+        self.log_index
+            .range((range.start_bound().cloned(), range.end_bound().cloned()))
+            .filter(|(_, pos)| self.live_pos(pos))
+            .take(limit)
+            .map(|(key, pos)| {
+                let value = readers.read_value(&self.log_dir_path, key, pos)?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        let result = self
+            .writer
+            .write()
+            .unwrap()
+            .unpin_logs(&self.pinned_file_ids);
+        if let Err(e) = result {
+            error!(
+                error = e.to_string().as_str(),
+                "fail to remove retired logs"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kvstore::{CompactionPolicy, KvStore, KvStoreOptions};
+    use crate::{KvsEngine, KvsSnapshot};
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn logs_in(dir: &PathBuf) -> BTreeSet<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let store = KvStore::open(store_dir("snapshot")).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        store.set("b".to_owned(), "2".to_owned()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+        // taking a snapshot copies nothing of the index
+        assert!(snapshot
+            .log_index
+            .ptr_eq(&store.writer.read().unwrap().log_index));
+        store.set("a".to_owned(), "3".to_owned()).unwrap();
+        store.remove("b".to_owned()).unwrap();
+        store.set("c".to_owned(), "4".to_owned()).unwrap();
+
+        assert_eq!(snapshot.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(snapshot.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(snapshot.get("c".to_owned()).unwrap(), None);
+        assert_eq!(
+            snapshot.scan_prefix(String::new(), 10).unwrap(),
+            vec![
+                ("a".to_owned(), "1".to_owned()),
+                ("b".to_owned(), "2".to_owned())
+            ]
+        );
+        assert_eq!(
+            store.scan_prefix(String::new(), 10).unwrap(),
+            vec![
+                ("a".to_owned(), "3".to_owned()),
+                ("c".to_owned(), "4".to_owned())
+            ]
+        );
+    }
+
+    // compaction leaves the logs a snapshot reads in place until it is dropped
+    #[test]
+    fn test_snapshot_pins_logs() {
+        let dir = store_dir("snapshot-pins");
+        let options = KvStoreOptions::new()
+            .segment_size(1024)
+            .compaction_policy(CompactionPolicy::SealedLogs(1));
+        let store = KvStore::open_with(&dir, options).unwrap();
+        for i in 0..100 {
+            store.set(format!("k{}", i), format!("v{}", i)).unwrap();
+        }
+        // the logs compacted so far are gone before the snapshot's logs are listed
+        thread::sleep(Duration::from_millis(500));
+        let snapshot = store.snapshot().unwrap();
+        let pinned = logs_in(&dir);
+        for round in 0..3 {
+            for i in 0..100 {
+                store
+                    .set(format!("k{}", i), format!("w{}-{}", round, i))
+                    .unwrap();
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        assert!(logs_in(&dir).is_superset(&pinned));
+        for i in 0..100 {
+            assert_eq!(
+                snapshot.get(format!("k{}", i)).unwrap(),
+                Some(format!("v{}", i))
+            );
+        }
+        drop(snapshot);
+        assert!(!logs_in(&dir).is_superset(&pinned));
+        assert_eq!(store.get("k0".to_owned()).unwrap(), Some("w2-0".to_owned()));
+    }
+}
@@ -0,0 +1,148 @@
+// fsync of the active log for the durability modes that do not sync inline:
+// group commit lets one writer fsync on behalf of every writer waiting so far,
+// periodic sync fsyncs from a background thread
+
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use tracing::error;
+
+use super::KvWriter;
+use crate::kvserror::Result;
+
+pub(super) struct GroupCommit {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct SyncState {
+    // every write up to this sequence number is on disk
+    synced_seq: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub(super) fn new() -> Self {
+        Self {
+            state: Mutex::new(SyncState::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    pub(super) fn mark_synced(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.synced_seq = state.synced_seq.max(seq);
+        self.synced.notify_all();
+    }
+
+    // block until the write numbered seq is on disk
+    pub(super) fn wait_synced(&self, seq: u64, writer: &RwLock<KvWriter>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced_seq < seq {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            // become the leader, every write made so far rides along
+            state.syncing = true;
+            drop(state);
+            let synced = sync_active_log(writer);
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.synced.notify_all();
+            state.synced_seq = state.synced_seq.max(synced?);
+        }
+        Ok(())
+    }
+}
+
+// fsync the active log without holding the writer lock
+// and return the sequence number of the last write it covers
+fn sync_active_log(writer: &RwLock<KvWriter>) -> Result<u64> {
+    let (file, seq) = {
+        let writer = writer.read().unwrap();
+        match writer.buf_writer.as_ref() {
+            Some(buf_writer) => (buf_writer.get_ref().try_clone()?, writer.written_seq),
+            // a read-only store has nothing to sync
+            None => return Ok(writer.written_seq),
+        }
+    };
+    file.sync_data()?;
+    Ok(seq)
+}
+
+// the syncer stops once every handle of the store is dropped
+pub(super) fn spawn_periodic_sync(
+    writer: Weak<RwLock<KvWriter>>,
+    group_commit: Arc<GroupCommit>,
+    interval: Duration,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        match sync_active_log(&writer) {
+            Ok(seq) => group_commit.mark_synced(seq),
+            Err(e) => error!(
+                error = e.to_string().as_str(),
+                "fail to sync the active log"
+            ),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::kvstore::{Durability, KvStore, KvStoreOptions};
+    use crate::KvsEngine;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(name: &str, durability: Durability) -> KvStore {
+        let options = KvStoreOptions::new().durability(durability);
+        KvStore::open_with(store_dir(name), options).unwrap()
+    }
+
+    // the sequence number of the last write known to be on disk
+    fn synced_seq(store: &KvStore) -> u64 {
+        store.group_commit.state.lock().unwrap().synced_seq
+    }
+
+    #[test]
+    fn test_sync_before_returning() {
+        let store = open("durability-sync", Durability::Sync);
+        for seq in 1..=3 {
+            store.set("a".to_owned(), seq.to_string()).unwrap();
+            assert_eq!(synced_seq(&store), seq);
+        }
+    }
+
+    #[test]
+    fn test_periodic_sync() {
+        let interval = Duration::from_millis(100);
+        let store = open("durability-periodic", Durability::Periodic(interval));
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        store.set("b".to_owned(), "2".to_owned()).unwrap();
+        thread::sleep(interval * 2);
+        assert_eq!(synced_seq(&store), 2);
+
+        // nothing syncs behind the writes of a store which does not ask for it
+        let store = open("durability-none", Durability::None);
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        thread::sleep(interval * 2);
+        assert_eq!(synced_seq(&store), 0);
+    }
+}
@@ -1,36 +1,276 @@
+mod batch;
+mod binary;
+mod change;
 mod client;
 mod kvserror;
 mod kvstore;
+mod range;
 mod server;
 mod sledstore;
+mod stats;
 pub mod threadpool;
+mod transaction;
 mod transmit;
+mod ttl;
 
-pub use client::KvsClient;
+pub use batch::{BatchOp, WriteBatch};
+pub use change::Change;
+pub use client::{KvsClient, Watch};
 pub use kvserror::{KvsError, Result};
-pub use kvstore::KvStore;
+pub use kvstore::{
+    CacheStats, CompactionPolicy, Compression, CompressionStats, Durability, KvStore,
+    KvStoreChanges, KvStoreOptions, KvStoreSnapshot, SegmentStats,
+};
+pub use range::{prefix_range, prefix_range_bytes};
 pub use server::KvsServer;
-pub use sledstore::SledKvsEngine;
+pub use sledstore::{SledKvsEngine, SledSnapshot};
+pub use stats::EngineStats;
 pub use threadpool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use transaction::Transaction;
 
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
+
+// keys and values are byte strings, the String methods are a convenience layer over them
+// which fails with KvsError::Utf8 on values that are not valid utf-8
 pub trait KvsEngine: Send + Clone + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    type Snapshot: KvsSnapshot;
+    type Changes: Iterator<Item = Result<Change>> + Send + 'static;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    // set a key which expires once ttl has passed
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    // swap the value of key from expected to new atomically, None standing for no such key,
+    // returns the current value instead if it is not the expected one
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>>;
+    // set key unless it exists, returns the existing value if it does
+    fn set_bytes_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.compare_and_swap_bytes(key, None, Some(value))? {
+            Ok(()) => Ok(None),
+            Err(current) => Ok(current),
+        }
+    }
+    // set key and return its previous value atomically
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    // apply every write of the batch, or none of them
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    // the value of key along with its version, which changes with every write to the key
+    fn get_bytes_with_version(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+    // write the batch only if every key read is still at the version it was read at,
+    // fails with KvsError::Conflict otherwise
+    fn commit_transaction(&self, reads: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()>;
+    // begin an optimistic transaction
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+    // the entries whose keys fall within range in key order, at most limit of them
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    // the entries whose keys start with prefix in key order, at most limit of them
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes(prefix_range_bytes(&prefix), limit)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+    fn get(&self, key: String) -> Result<Option<String>> {
+        binary::into_string_opt(self.get_bytes(key.into_bytes())?)
+    }
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let swapped = self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match swapped {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(binary::into_string_opt(current)?)),
+        }
+    }
+    fn set_if_absent(&self, key: String, value: String) -> Result<Option<String>> {
+        binary::into_string_opt(self.set_bytes_if_absent(key.into_bytes(), value.into_bytes())?)
+    }
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        binary::into_string_opt(self.get_set_bytes(key.into_bytes(), value.into_bytes())?)
+    }
+    fn scan(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let range = (
+            binary::bound_bytes(range.start_bound()),
+            binary::bound_bytes(range.end_bound()),
+        );
+        binary::into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+    fn scan_prefix(&self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        binary::into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit)?)
+    }
+
+    // a read-only view of the engine as of now,
+    // which keeps seeing the same data however the engine changes after
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    // copy the engine into dir, which must not hold a store yet, while it keeps serving;
+    // the copy is consistent as of one moment and opens as a store of its own
+    fn backup_to(&self, dir: impl AsRef<Path>) -> Result<()>;
+    // replace the content of the engine with a backup taken by backup_to
+    fn restore_from(&self, dir: impl AsRef<Path>) -> Result<()>;
+
+    // the size of the engine and how much of it is garbage, for monitoring
+    fn stats(&self) -> Result<EngineStats>;
+
+    // the sets and removes written after seq in commit order, 0 standing for the beginning;
+    // the iterator returns None once caught up and the writes made since when polled again.
+    // fails with KvsError::ChangesCompacted if compaction has dropped writes after seq
+    fn changes_since(&self, seq: u64) -> Result<Self::Changes>;
+    // the sequence number of the last write, from which a feed of the writes to come starts
+    fn last_seq(&self) -> Result<u64>;
+    // block until a write after seq is made or timeout has passed,
+    // and return the sequence number of the last write
+    fn wait_for_write(&self, seq: u64, timeout: Duration) -> Result<u64>;
+}
+
+// the reads of KvsEngine, against the engine as of the moment the snapshot was taken
+pub trait KvsSnapshot: Send + 'static {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan_bytes(prefix_range_bytes(&prefix), limit)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        binary::into_string_opt(self.get_bytes(key.into_bytes())?)
+    }
+    fn scan(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let range = (
+            binary::bound_bytes(range.start_bound()),
+            binary::bound_bytes(range.end_bound()),
+        );
+        binary::into_string_pairs(self.scan_bytes(range, limit)?)
+    }
+    fn scan_prefix(&self, prefix: String, limit: usize) -> Result<Vec<(String, String)>> {
+        binary::into_string_pairs(self.scan_prefix_bytes(prefix.into_bytes(), limit)?)
+    }
 }
 
 use serde::{Deserialize, Serialize};
 
+// requests and responses travel as bson documents,
+// keys and values as bson binary
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum KSP {
-    Set(String, String),
-    Get(String),
-    Rm(String),
+    Set(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary")] Vec<u8>,
+    ),
+    // a set whose key expires after the ttl in milliseconds
+    SetWithTtl(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary")] Vec<u8>,
+        u64,
+    ),
+    Get(#[serde(with = "binary")] Vec<u8>),
+    Rm(#[serde(with = "binary")] Vec<u8>),
+    // key, expected value, new value
+    Cas(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary::option")] Option<Vec<u8>>,
+        #[serde(with = "binary::option")] Option<Vec<u8>>,
+    ),
+    SetIfAbsent(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary")] Vec<u8>,
+    ),
+    GetSet(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary")] Vec<u8>,
+    ),
+    Batch(WriteBatch),
+    // one page of the keys within (start, end), at most limit of them
+    Scan(
+        #[serde(with = "binary::bound")] Bound<Vec<u8>>,
+        #[serde(with = "binary::bound")] Bound<Vec<u8>>,
+        usize,
+    ),
+    // take a snapshot which lives as long as the connection or until released
+    Snapshot(()),
+    SnapshotGet(u64, #[serde(with = "binary")] Vec<u8>),
+    SnapshotScan(
+        u64,
+        #[serde(with = "binary::bound")] Bound<Vec<u8>>,
+        #[serde(with = "binary::bound")] Bound<Vec<u8>>,
+        usize,
+    ),
+    ReleaseSnapshot(u64),
+    // begin a transaction for the connection, at most one at a time
+    Begin(()),
+    TxnGet(#[serde(with = "binary")] Vec<u8>),
+    TxnSet(
+        #[serde(with = "binary")] Vec<u8>,
+        #[serde(with = "binary")] Vec<u8>,
+    ),
+    TxnRm(#[serde(with = "binary")] Vec<u8>),
+    Commit(()),
+    Rollback(()),
+    // the statistics of the engine
+    Stats(()),
+    // take a backup, which the server sends back as BackupFile chunks followed by Ok
+    Backup(()),
+    // a chunk of a file of a backup to restore, kept for the connection until Restore
+    RestoreFile(String, #[serde(with = "binary")] Vec<u8>),
+    // replace the content of the engine with the backup sent over the connection
+    Restore(()),
+    // key or prefix, whether it is a prefix, and the sequence number of the last change seen;
+    // turns the connection into a stream of Change responses for the matching keys,
+    // which starts after that change, or from now on if there is none
+    Watch(#[serde(with = "binary")] Vec<u8>, bool, Option<u64>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    OkWith(Option<String>),
+    OkWith(#[serde(with = "binary::option")] Option<Vec<u8>>),
+    // a page of a scan and the key to continue after, if the scan goes on
+    Scan(
+        #[serde(with = "binary::pairs")] Vec<(Vec<u8>, Vec<u8>)>,
+        #[serde(with = "binary::option")] Option<Vec<u8>>,
+    ),
     Ok(()),
+    // a conditional write did not apply, carrying the current value
+    Mismatch(#[serde(with = "binary::option")] Option<Vec<u8>>),
+    // the id of a snapshot taken for the connection
+    Snapshot(u64),
+    // the transaction conflicted with another write and was dropped
+    Conflict(()),
+    Stats(EngineStats),
+    // a chunk of a file of the backup, the chunks of a file in order
+    BackupFile(String, #[serde(with = "binary")] Vec<u8>),
+    // a watch has started after the given sequence number
+    Watching(u64),
+    Change(Change),
     Err(String),
 }
@@ -0,0 +1,52 @@
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+
+// the keys starting with prefix are exactly the keys within this range
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    // the end is the smallest string greater than every string starting with prefix
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(c) = end.pop() {
+        if let Some(next) = next_char(c) {
+            end.push(next);
+            return (
+                Included(prefix.to_owned()),
+                Excluded(end.into_iter().collect()),
+            );
+        }
+    }
+    (Included(prefix.to_owned()), Unbounded)
+}
+
+// the byte string keys starting with prefix are exactly the keys within this range
+pub fn prefix_range_bytes(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // the end is the prefix with its last byte below 0xff bumped and the rest dropped
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return (Included(prefix.to_vec()), Excluded(end));
+        }
+    }
+    (Included(prefix.to_vec()), Unbounded)
+}
+
+// whether no key can fall within range,
+// BTreeMap::range even panics on some of these
+pub(crate) fn is_empty_range<T: Ord>(range: &impl RangeBounds<T>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Included(start), Included(end)) => start > end,
+        (Included(start), Excluded(end))
+        | (Excluded(start), Included(end))
+        | (Excluded(start), Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+fn next_char(c: char) -> Option<char> {
+    match c {
+        char::MAX => None,
+        // skip the surrogates, which are not chars
+        '\u{D7FF}' => Some('\u{E000}'),
+        c => char::from_u32(c as u32 + 1),
+    }
+}
@@ -1,19 +1,34 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
+    collections::HashMap,
+    env, fs,
+    io::{BufReader, BufWriter, ErrorKind, Write},
     net::{SocketAddr, TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    ops::Bound,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
 };
 
-use serde_json::Deserializer;
 use tracing::info;
 
 use crate::{
-    threadpool::ThreadPool, transmit::to_bytes, KvsEngine, KvsError, Response, Result, KSP,
+    threadpool::ThreadPool,
+    transmit::{read_message, send_files, to_bytes, FileReceiver},
+    Change, KvsEngine, KvsError, KvsSnapshot, Response, Result, Transaction, KSP,
 };
 
+// the most entries a single scan response carries
+const MAX_SCAN_PAGE: usize = 1024;
+// how long a watch which has caught up waits for writes before it checks on the client
+const WATCH_IDLE: Duration = Duration::from_millis(500);
+
+type Range = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+type Page = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     addr: SocketAddr,
-    engine: Arc<Mutex<E>>,
+    engine: E,
     threadpool: T,
 }
 
@@ -21,7 +36,7 @@ impl<E: KvsEngine + 'static, T: ThreadPool> KvsServer<E, T> {
     pub fn new(addr: SocketAddr, engine: E, threadpool: T) -> Self {
         Self {
             addr,
-            engine: Arc::new(Mutex::new(engine)),
+            engine,
             threadpool,
         }
     }
@@ -30,6 +45,7 @@ impl<E: KvsEngine + 'static, T: ThreadPool> KvsServer<E, T> {
         info!("server run TBD");
         let listener = TcpListener::bind(self.addr)?;
         for stream in listener.incoming() {
+            // every connection works on its own handle of the engine
             let engine = self.engine.clone();
             self.threadpool.spawn(move || {
                 if let Ok(s) = stream {
@@ -51,32 +67,485 @@ fn send_resp(writer: &mut impl Write, resp: Response) -> Result<()> {
     writer.flush().map_err(KvsError::IoError)
 }
 
-fn serve<E: KvsEngine>(engine: Arc<Mutex<E>>, stream: TcpStream) -> Result<()> {
-    let reader = BufReader::new(&stream);
+fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
-    let commands = Deserializer::from_reader(reader).into_iter::<KSP>();
-    for command in commands {
-        let command = command?;
+    // the snapshots taken over this connection, dropped along with it
+    let mut snapshots = HashMap::new();
+    let mut next_snapshot_id = 0;
+    // the transaction in progress over this connection, rolled back along with it
+    let mut txn: Option<Transaction<E>> = None;
+    // the backup being sent over this connection to restore, removed along with it
+    let mut restore: Option<StagedBackup> = None;
+    while let Some(command) = read_message::<KSP>(&mut reader)? {
         info!(
             command = format!("{:?}", command).as_str(),
             "receive command"
         );
-        let mut guard = engine.lock().unwrap();
         match command {
-            KSP::Get(key) => match guard.get(key) {
+            KSP::Get(key) => match engine.get_bytes(key) {
                 Ok(v) => send_resp(&mut writer, Response::OkWith(v)),
                 Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
             },
-            KSP::Rm(key) => match guard.remove(key) {
+            KSP::Rm(key) => match engine.remove_bytes(key) {
+                Ok(_) => send_resp(&mut writer, Response::Ok(())),
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::Set(key, val) => match engine.set_bytes(key, val) {
                 Ok(_) => send_resp(&mut writer, Response::Ok(())),
                 Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
             },
-            KSP::Set(key, val) => match guard.set(key, val) {
+            KSP::SetWithTtl(key, val, ttl) => {
+                match engine.set_bytes_with_ttl(key, val, Duration::from_millis(ttl)) {
+                    Ok(_) => send_resp(&mut writer, Response::Ok(())),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::Cas(key, expected, new) => {
+                match engine.compare_and_swap_bytes(key, expected, new) {
+                    Ok(Ok(())) => send_resp(&mut writer, Response::Ok(())),
+                    Ok(Err(current)) => send_resp(&mut writer, Response::Mismatch(current)),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::SetIfAbsent(key, val) => match engine.set_bytes_if_absent(key, val) {
+                Ok(None) => send_resp(&mut writer, Response::Ok(())),
+                Ok(Some(current)) => send_resp(&mut writer, Response::Mismatch(Some(current))),
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::GetSet(key, val) => match engine.get_set_bytes(key, val) {
+                Ok(v) => send_resp(&mut writer, Response::OkWith(v)),
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::Batch(batch) => match engine.write_batch(batch) {
                 Ok(_) => send_resp(&mut writer, Response::Ok(())),
                 Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
             },
+            KSP::Scan(start, end, limit) => {
+                let scan = |range, limit| engine.scan_bytes(range, limit);
+                match scan_page(scan, start, end, limit) {
+                    Ok((entries, next)) => send_resp(&mut writer, Response::Scan(entries, next)),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::Snapshot(()) => match engine.snapshot() {
+                Ok(snapshot) => {
+                    next_snapshot_id += 1;
+                    snapshots.insert(next_snapshot_id, snapshot);
+                    send_resp(&mut writer, Response::Snapshot(next_snapshot_id))
+                }
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::SnapshotGet(id, key) => {
+                let result = snapshots
+                    .get(&id)
+                    .ok_or(KvsError::SnapshotNotFound(id))
+                    .and_then(|snapshot: &E::Snapshot| snapshot.get_bytes(key));
+                match result {
+                    Ok(v) => send_resp(&mut writer, Response::OkWith(v)),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::SnapshotScan(id, start, end, limit) => {
+                let result = snapshots
+                    .get(&id)
+                    .ok_or(KvsError::SnapshotNotFound(id))
+                    .and_then(|snapshot: &E::Snapshot| {
+                        let scan = |range, limit| snapshot.scan_bytes(range, limit);
+                        scan_page(scan, start, end, limit)
+                    });
+                match result {
+                    Ok((entries, next)) => send_resp(&mut writer, Response::Scan(entries, next)),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::ReleaseSnapshot(id) => match snapshots.remove(&id) {
+                Some(_) => send_resp(&mut writer, Response::Ok(())),
+                None => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::SnapshotNotFound(id).to_string()),
+                ),
+            },
+            KSP::Begin(()) => match txn {
+                Some(_) => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::TransactionInProgress.to_string()),
+                ),
+                None => {
+                    txn = Some(engine.begin());
+                    send_resp(&mut writer, Response::Ok(()))
+                }
+            },
+            KSP::TxnGet(key) => {
+                let result = txn
+                    .as_mut()
+                    .ok_or(KvsError::NoTransaction)
+                    .and_then(|txn| txn.get_bytes(key));
+                match result {
+                    Ok(v) => send_resp(&mut writer, Response::OkWith(v)),
+                    // the transaction is bound to conflict on commit, so it ends here
+                    Err(KvsError::Conflict) => {
+                        txn = None;
+                        send_resp(&mut writer, Response::Conflict(()))
+                    }
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::TxnSet(key, val) => match txn.as_mut() {
+                Some(txn) => {
+                    txn.set_bytes(key, val);
+                    send_resp(&mut writer, Response::Ok(()))
+                }
+                None => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::NoTransaction.to_string()),
+                ),
+            },
+            KSP::TxnRm(key) => match txn.as_mut() {
+                Some(txn) => {
+                    txn.remove_bytes(key);
+                    send_resp(&mut writer, Response::Ok(()))
+                }
+                None => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::NoTransaction.to_string()),
+                ),
+            },
+            KSP::Commit(()) => match txn.take().map(Transaction::commit) {
+                Some(Ok(())) => send_resp(&mut writer, Response::Ok(())),
+                Some(Err(KvsError::Conflict)) => send_resp(&mut writer, Response::Conflict(())),
+                Some(Err(e)) => send_resp(&mut writer, Response::Err(e.to_string())),
+                None => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::NoTransaction.to_string()),
+                ),
+            },
+            KSP::Rollback(()) => match txn.take() {
+                Some(_) => send_resp(&mut writer, Response::Ok(())),
+                None => send_resp(
+                    &mut writer,
+                    Response::Err(KvsError::NoTransaction.to_string()),
+                ),
+            },
+            KSP::Stats(()) => match engine.stats() {
+                Ok(stats) => send_resp(&mut writer, Response::Stats(stats)),
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::Backup(()) => match send_backup(&engine, &mut writer) {
+                Ok(()) => send_resp(&mut writer, Response::Ok(())),
+                Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+            },
+            KSP::RestoreFile(name, chunk) => {
+                let received = match restore.as_mut() {
+                    Some(staged) => staged.files.receive(name, &chunk),
+                    None => StagedBackup::new().and_then(|mut staged| {
+                        staged.files.receive(name, &chunk)?;
+                        restore = Some(staged);
+                        Ok(())
+                    }),
+                };
+                match received {
+                    Ok(()) => send_resp(&mut writer, Response::Ok(())),
+                    Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                }
+            }
+            KSP::Restore(()) => match restore.take() {
+                Some(mut staged) => {
+                    let restored = staged
+                        .files
+                        .finish()
+                        .and_then(|()| engine.restore_from(staged.files.dir()));
+                    match restored {
+                        Ok(()) => send_resp(&mut writer, Response::Ok(())),
+                        Err(e) => send_resp(&mut writer, Response::Err(e.to_string())),
+                    }
+                }
+                None => send_resp(&mut writer, Response::Err(KvsError::NoBackup.to_string())),
+            },
+            // the connection only streams changes from now on, and ends along with the watch,
+            // which runs on a thread of its own so as not to hold on to one of the pool
+            KSP::Watch(key, prefix, since) => {
+                let stream = stream.try_clone()?;
+                thread::spawn(move || {
+                    let _ = watch(&engine, &stream, key, prefix, since);
+                    info!("finish one watch!");
+                });
+                return Ok(());
+            }
         }?;
         info!("finish processing command");
     }
     Ok(())
 }
+
+// send the changes to key, or to the keys with it as a prefix, until the client goes away
+fn watch<E: KvsEngine>(
+    engine: &E,
+    stream: &TcpStream,
+    key: Vec<u8>,
+    prefix: bool,
+    since: Option<u64>,
+) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+    let started = match since {
+        Some(seq) => Ok(seq),
+        None => engine.last_seq(),
+    }
+    .and_then(|seq| Ok((seq, engine.changes_since(seq)?)));
+    // the sequence number of the last write the watch has caught up with
+    let (mut seq, mut changes) = match started {
+        Ok((seq, changes)) => {
+            send_resp(&mut writer, Response::Watching(seq))?;
+            (seq, changes)
+        }
+        Err(e) => return send_resp(&mut writer, Response::Err(e.to_string())),
+    };
+    let matches = |change: &Change| match prefix {
+        true => change.key.starts_with(&key),
+        false => change.key == key,
+    };
+    loop {
+        match changes.next() {
+            Some(Ok(change)) => {
+                seq = seq.max(change.seq);
+                if matches(&change) {
+                    send_resp(&mut writer, Response::Change(change))?;
+                }
+            }
+            // the watch cannot go on without skipping changes
+            Some(Err(e)) => return send_resp(&mut writer, Response::Err(e.to_string())),
+            None => {
+                // a write which passes nothing on is waited past as well
+                seq = match engine.wait_for_write(seq, WATCH_IDLE) {
+                    Ok(written_seq) => seq.max(written_seq),
+                    Err(e) => return send_resp(&mut writer, Response::Err(e.to_string())),
+                };
+                if client_gone(stream)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// whether the client has closed the connection, which is all a client does once watching,
+// so anything it sends ends the watch as well
+fn client_gone(stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(&mut [0; 1]);
+    stream.set_nonblocking(false)?;
+    match peeked {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// take a backup into a temporary dir and send its files
+fn send_backup<E: KvsEngine>(engine: &E, writer: &mut impl Write) -> Result<()> {
+    let staged = StagedBackup::new()?;
+    engine.backup_to(staged.files.dir())?;
+    send_files(staged.files.dir(), |name, chunk| {
+        send_resp(writer, Response::BackupFile(name, chunk))
+    })
+}
+
+// a backup on its way through the server, in a temporary dir removed once dropped
+struct StagedBackup {
+    files: FileReceiver,
+}
+
+impl StagedBackup {
+    fn new() -> Result<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let dir = env::temp_dir().join(format!(
+            "kvs-backup-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            files: FileReceiver::new(dir),
+        })
+    }
+}
+
+impl Drop for StagedBackup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.files.dir());
+    }
+}
+
+// scan one page and find out whether the scan goes on after it
+fn scan_page(
+    scan: impl FnOnce(Range, usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    limit: usize,
+) -> Result<Page> {
+    let limit = limit.clamp(1, MAX_SCAN_PAGE);
+    // one entry more than the page tells that there are more to come
+    let mut entries = scan((start, end), limit + 1)?;
+    if entries.len() > limit {
+        entries.truncate(limit);
+        let next = entries.last().map(|(key, _)| key.clone());
+        Ok((entries, next))
+    } else {
+        Ok((entries, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_gone, MAX_SCAN_PAGE};
+    use crate::{Change, KvStore, KvsClient, KvsServer, Result, SharedQueueThreadPool, ThreadPool};
+    use std::env::temp_dir;
+    use std::fs;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::ops::Bound;
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("kvs-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // a server with a thread in its pool for a client and one more,
+    // which the watches must not hold on to
+    fn start_server(name: &str) -> SocketAddr {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let engine = KvStore::open(store_dir(name)).unwrap();
+        let threadpool = SharedQueueThreadPool::new(2).unwrap();
+        thread::spawn(move || KvsServer::new(addr, engine, threadpool).run());
+        for _ in 0..100 {
+            if TcpStream::connect(addr).is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        addr
+    }
+
+    fn change(seq: u64, key: &str, value: Option<&str>) -> Change {
+        Change {
+            seq,
+            key: key.into(),
+            value: value.map(Into::into),
+        }
+    }
+
+    fn next_changes(watch: &mut impl Iterator<Item = Result<Change>>, n: usize) -> Vec<Change> {
+        watch.take(n).map(|change| change.unwrap()).collect()
+    }
+
+    // every key in order, one page after another
+    fn scan_all(client: &mut KvsClient, limit: usize) -> (Vec<Vec<u8>>, usize) {
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        let mut start = Bound::Unbounded;
+        loop {
+            let (entries, next) = client.scan(start, Bound::Unbounded, limit).unwrap();
+            assert!(entries.len() <= limit.min(MAX_SCAN_PAGE));
+            keys.extend(entries.into_iter().map(|(key, _)| key));
+            pages += 1;
+            match next {
+                Some(next) => start = Bound::Excluded(next),
+                None => return (keys, pages),
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_pages() {
+        let addr = start_server("scan-pages");
+        let mut client = KvsClient::new(addr).unwrap();
+        let n = MAX_SCAN_PAGE * 2 + 100;
+        let expected: Vec<Vec<u8>> = (0..n).map(|i| format!("k{:05}", i).into_bytes()).collect();
+        for key in expected.iter() {
+            client.set(key.clone(), "v").unwrap();
+        }
+        // a limit beyond the largest page is cut down to it
+        assert_eq!(
+            scan_all(&mut client, MAX_SCAN_PAGE * 4),
+            (expected.clone(), 3)
+        );
+        assert_eq!(scan_all(&mut client, 7), (expected.clone(), n.div_ceil(7)));
+        // a scan ending on a page boundary says so on that page
+        let (entries, next) = client
+            .scan(Bound::Unbounded, Bound::Excluded(b"k00014".to_vec()), 7)
+            .unwrap();
+        assert_eq!((entries.len(), next), (7, Some(b"k00006".to_vec())));
+        let (entries, next) = client
+            .scan(
+                Bound::Excluded(b"k00006".to_vec()),
+                Bound::Excluded(b"k00014".to_vec()),
+                7,
+            )
+            .unwrap();
+        assert_eq!((entries.len(), next), (7, None));
+    }
+
+    #[test]
+    fn test_watch() {
+        let addr = start_server("watch");
+        let mut client = KvsClient::new(addr).unwrap();
+        client.set("a", "0").unwrap();
+
+        let mut key_watch = KvsClient::new(addr)
+            .unwrap()
+            .watch("a", false, None)
+            .unwrap();
+        let mut prefix_watch = KvsClient::new(addr)
+            .unwrap()
+            .watch("a", true, None)
+            .unwrap();
+        assert_eq!(key_watch.started_after(), 1);
+        client.set("a", "1").unwrap();
+        client.set("ab", "2").unwrap();
+        client.set("b", "3").unwrap();
+        client.remove("a").unwrap();
+        assert_eq!(
+            next_changes(&mut key_watch, 2),
+            vec![change(2, "a", Some("1")), change(5, "a", None)]
+        );
+        assert_eq!(
+            next_changes(&mut prefix_watch, 3),
+            vec![
+                change(2, "a", Some("1")),
+                change(3, "ab", Some("2")),
+                change(5, "a", None)
+            ]
+        );
+
+        // a watch resumes after the last change seen
+        drop(key_watch);
+        let mut resumed = KvsClient::new(addr)
+            .unwrap()
+            .watch("a", true, Some(3))
+            .unwrap();
+        assert_eq!(resumed.started_after(), 3);
+        client.set("ac", "4").unwrap();
+        assert_eq!(
+            next_changes(&mut resumed, 2),
+            vec![change(5, "a", None), change(6, "ac", Some("4"))]
+        );
+    }
+
+    #[test]
+    fn test_client_gone() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert!(!client_gone(&stream).unwrap());
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+        assert!(client_gone(&stream).unwrap());
+    }
+}
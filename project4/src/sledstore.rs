@@ -1,42 +1,479 @@
+use std::ops::RangeBounds;
 use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
 
-use crate::{KvsEngine, KvsError, Result};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::{IVec, Transactional};
+use tracing::error;
+
+use crate::range::is_empty_range;
+use crate::ttl::{expire_at, now_millis, EXPIRY_INTERVAL};
+use crate::{BatchOp, Change, EngineStats, KvsEngine, KvsError, KvsSnapshot, Result, WriteBatch};
+
+// the tree holding the expiry time of every key with a ttl
+const TTL_TREE: &str = "__kvs_ttl";
+// the tree holding the version of every key, which changes with every write to it
+const VERSION_TREE: &str = "__kvs_versions";
+// the version of a key which does not exist
+const ABSENT_VERSION: u64 = 0;
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    db: sled::Db,
+    db: Arc<sled::Db>,
+    ttl: sled::Tree,
+    versions: sled::Tree,
+}
+
+// the trees of the engine within a transaction,
+// writes go through here so that the ttl and the version of a key follow its value
+struct Trees<'a> {
+    db: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
 }
 
+// only a commit running into a conflict aborts its transaction
+type TxResult<T> = ConflictableTransactionResult<T, ()>;
+
 impl SledKvsEngine {
     pub fn open<T>(path: T) -> Result<Self>
     where
         T: AsRef<Path> + std::fmt::Debug,
     {
-        Ok(SledKvsEngine {
-            db: sled::open(&path)?,
-        })
+        let engine = Self::with_db(Arc::new(sled::open(&path)?))?;
+        engine.drop_stale_versions()?;
+        spawn_expirer(Arc::downgrade(&engine.db));
+        Ok(engine)
+    }
+
+    fn with_db(db: Arc<sled::Db>) -> Result<Self> {
+        let ttl = db.open_tree(TTL_TREE)?;
+        let versions = db.open_tree(VERSION_TREE)?;
+        Ok(SledKvsEngine { db, ttl, versions })
+    }
+
+    // the versions of removed keys used to be kept, those left over are dropped
+    fn drop_stale_versions(&self) -> Result<()> {
+        for entry in self.versions.iter() {
+            let (key, _) = entry?;
+            if !self.db.contains_key(&key)? {
+                self.versions.remove(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // run f as one transaction over the trees of the engine and flush it
+    fn transact<T>(&self, f: impl Fn(&Trees) -> TxResult<T>) -> Result<T> {
+        let result = (&**self.db, &self.ttl, &self.versions)
+            .transaction(|(db, ttl, versions)| f(&Trees { db, ttl, versions }))
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(result)
+    }
+
+    // the entries of a range or a prefix scan, leaving out the expired ones
+    fn collect_live(&self, entries: sled::Iter, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut live = Vec::new();
+        for entry in entries {
+            if live.len() >= limit {
+                break;
+            }
+            let (key, value) = entry?;
+            if !self.is_expired(&key, now)? {
+                live.push((key.to_vec(), value.to_vec()));
+            }
+        }
+        Ok(live)
+    }
+
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(self
+            .ttl
+            .get(key)?
+            .is_some_and(|expire_at| decode_u64(&expire_at) <= now))
+    }
+
+    // remove key if its ttl ran out by now
+    fn expire_key(&self, key: &[u8], now: u64) -> Result<()> {
+        self.transact(|trees| trees.live_value(key, now).map(|_| ()))
+    }
+
+    fn expire_keys(&self, now: u64) -> Result<()> {
+        for entry in self.ttl.iter() {
+            let (key, expire_at) = entry?;
+            if decode_u64(&expire_at) <= now {
+                self.expire_key(&key, now)?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&self, key: String) -> crate::Result<Option<String>> {
+    type Snapshot = SledSnapshot;
+    type Changes = std::iter::Empty<Result<Change>>;
+
+    fn get_bytes(&self, key: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        if self.is_expired(&key, now)? {
+            self.expire_key(&key, now)?;
+            return Ok(None);
+        }
+        Ok(self.db.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<()> {
+        self.transact(|trees| trees.insert(&key, &value, None))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> crate::Result<()> {
+        let expire_at = expire_at(ttl);
+        self.transact(|trees| trees.insert(&key, &value, Some(expire_at)))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> crate::Result<()> {
+        let now = now_millis();
+        let removed = self.transact(|trees| match trees.live_value(&key, now)? {
+            Some(_) => trees.remove(&key).map(|_| true),
+            None => Ok(false),
+        })?;
+        if !removed {
+            return Err(key_not_found(&key));
+        }
+        Ok(())
+    }
+
+    // a transaction rather than a plain compare and swap of the tree,
+    // so that the ttl and the version of the key are swapped along with its value
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> crate::Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let now = now_millis();
+        let swapped = self.transact(|trees| {
+            let current = trees.live_value(&key, now)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(Err(current));
+            }
+            match new.as_ref() {
+                Some(value) => trees.insert(&key, value, None)?,
+                None => {
+                    trees.remove(&key)?;
+                }
+            };
+            Ok(Ok(()))
+        })?;
+        match swapped {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(|v| v.to_vec()))),
+        }
+    }
+
+    fn get_set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        let now = now_millis();
+        let current = self.transact(|trees| {
+            let current = trees.live_value(&key, now)?;
+            trees.insert(&key, &value, None)?;
+            Ok(current)
+        })?;
+        Ok(current.map(|v| v.to_vec()))
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        self.collect_live(self.db.range(range), limit)
+    }
+
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.collect_live(self.db.scan_prefix(prefix), limit)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        self.transact(|trees| trees.apply_batch(&batch))
+    }
+
+    fn get_bytes_with_version(&self, key: Vec<u8>) -> crate::Result<(Option<Vec<u8>>, u64)> {
+        let now = now_millis();
+        let (value, version) = self.transact(|trees| {
+            let value = trees.live_value(&key, now)?;
+            Ok((value, trees.version(&key)?))
+        })?;
+        Ok((value.map(|v| v.to_vec()), version))
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, u64)>,
+        batch: WriteBatch,
+    ) -> crate::Result<()> {
+        let now = now_millis();
+        self.transact(|trees| {
+            for (key, version) in reads.iter() {
+                // a key which expired since it was read has changed as well
+                trees.live_value(key, now)?;
+                if trees.version(key)? != *version {
+                    return Err(ConflictableTransactionError::Abort(()));
+                }
+            }
+            trees.apply_batch(&batch)
+        })
+    }
+
+    fn snapshot(&self) -> crate::Result<SledSnapshot> {
+        Err(KvsError::Unsupported("snapshots".to_owned()))
+    }
+
+    fn backup_to(&self, _dir: impl AsRef<Path>) -> Result<()> {
+        Err(KvsError::Unsupported("backups".to_owned()))
+    }
+
+    fn restore_from(&self, _dir: impl AsRef<Path>) -> Result<()> {
+        Err(KvsError::Unsupported("backups".to_owned()))
+    }
+
+    fn changes_since(&self, _seq: u64) -> Result<Self::Changes> {
+        Err(KvsError::Unsupported("change feeds".to_owned()))
+    }
+
+    fn last_seq(&self) -> Result<u64> {
+        Err(KvsError::Unsupported("change feeds".to_owned()))
+    }
+
+    fn wait_for_write(&self, _seq: u64, _timeout: Duration) -> Result<u64> {
+        Err(KvsError::Unsupported("change feeds".to_owned()))
+    }
+
+    // sled compacts its own files and tells nothing about it
+    fn stats(&self) -> Result<EngineStats> {
+        let now = now_millis();
+        let mut expired = 0;
+        for entry in self.ttl.iter() {
+            let (_, expire_at) = entry?;
+            if decode_u64(&expire_at) <= now {
+                expired += 1;
+            }
+        }
+        Ok(EngineStats {
+            live_keys: (self.db.len() as u64).saturating_sub(expired),
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
+}
+
+impl Trees<'_> {
+    // the value of key, an expired key counts as absent and is removed
+    fn live_value(&self, key: &[u8], now: u64) -> TxResult<Option<IVec>> {
+        if let Some(expire_at) = self.ttl.get(key)? {
+            if decode_u64(&expire_at) <= now {
+                self.remove(key)?;
+                return Ok(None);
+            }
+        }
+        Ok(self.db.get(key)?)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8], expire_at: Option<u64>) -> TxResult<()> {
+        self.db.insert(key, value)?;
+        match expire_at {
+            Some(expire_at) => self.ttl.insert(key, &expire_at.to_be_bytes())?,
+            None => self.ttl.remove(key)?,
+        };
+        self.bump_version(key)
+    }
+
+    // returns the removed value, removing a key which does not exist changes nothing;
+    // the version of a removed key goes along with it
+    fn remove(&self, key: &[u8]) -> TxResult<Option<IVec>> {
+        let removed = self.db.remove(key)?;
+        self.ttl.remove(key)?;
+        self.versions.remove(key)?;
+        Ok(removed)
+    }
+
+    fn apply_batch(&self, batch: &WriteBatch) -> TxResult<()> {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Set(key, value) => self.insert(key, value, None)?,
+                BatchOp::Rm(key) => {
+                    self.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn version(&self, key: &[u8]) -> TxResult<u64> {
         Ok(self
-            .db
+            .versions
             .get(key)?
-            .map(|v| v.as_ref().to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+            .map_or(ABSENT_VERSION, |v| decode_u64(&v)))
     }
 
-    fn set(&self, key: String, value: String) -> crate::Result<()> {
-        self.db.insert(key, value.as_bytes()).map(|_| ())?;
-        self.db.flush()?;
+    // versions are drawn from the ids sled hands out, which never repeat,
+    // so that a key removed and written again never goes back to a version it had before
+    fn bump_version(&self, key: &[u8]) -> TxResult<()> {
+        let version = (self.db.generate_id()? + 1).max(self.version(key)? + 1);
+        self.versions.insert(key, &version.to_be_bytes())?;
         Ok(())
     }
+}
 
-    fn remove(&self, key: String) -> crate::Result<()> {
-        self.db.remove(&key)?.ok_or(KvsError::KeyNotFound(key))?;
-        self.db.flush()?;
-        Ok(())
+// sled keeps no older versions of its data to read a snapshot from,
+// so no snapshot of it is ever taken
+pub enum SledSnapshot {}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, _key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match *self {}
+    }
+
+    fn scan_bytes(
+        &self,
+        _range: impl RangeBounds<Vec<u8>>,
+        _limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match *self {}
+    }
+}
+
+fn key_not_found(key: &[u8]) -> KvsError {
+    KvsError::KeyNotFound(String::from_utf8_lossy(key).into_owned())
+}
+
+fn transaction_error(e: TransactionError<()>) -> KvsError {
+    match e {
+        TransactionError::Storage(e) => KvsError::Sled(e),
+        TransactionError::Abort(()) => KvsError::Conflict,
+    }
+}
+
+// expiry times and versions are stored big endian
+fn decode_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("malformed u64"))
+}
+
+// the expirer stops once every handle of the engine is dropped
+fn spawn_expirer(db: Weak<sled::Db>) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_INTERVAL);
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => break,
+        };
+        let result = SledKvsEngine::with_db(db).and_then(|engine| engine.expire_keys(now_millis()));
+        if let Err(e) = result {
+            error!(error = e.to_string().as_str(), "fail to expire keys");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SledKvsEngine;
+    use crate::{KvsEngine, KvsError, WriteBatch};
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let store = SledKvsEngine::open(store_dir("sled-cas")).unwrap();
+        let some = |value: &str| Some(value.to_owned());
+        // expected None stands for an absent key
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), None, some("1"))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), None, some("2"))
+                .unwrap(),
+            Err(some("1"))
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), some("1"), some("2"))
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), some("1"), some("3"))
+                .unwrap(),
+            Err(some("2"))
+        );
+        assert_eq!(store.get("a".to_owned()).unwrap(), some("2"));
+        // new None removes the key
+        assert_eq!(
+            store
+                .compare_and_swap("a".to_owned(), some("2"), None)
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(store.get("a".to_owned()).unwrap(), None);
+
+        // the ttl of the key is swapped along with its value
+        store
+            .set_with_ttl("b".to_owned(), "1".to_owned(), Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(
+            store
+                .compare_and_swap("b".to_owned(), some("1"), some("2"))
+                .unwrap(),
+            Ok(())
+        );
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("b".to_owned()).unwrap(), some("2"));
+    }
+
+    #[test]
+    fn test_versions_go_with_keys() {
+        let store = SledKvsEngine::open(store_dir("sled-versions")).unwrap();
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        let (_, version) = store.get_bytes_with_version(b"a".to_vec()).unwrap();
+        store.remove("a".to_owned()).unwrap();
+        store
+            .set_with_ttl("b".to_owned(), "2".to_owned(), Duration::from_millis(1))
+            .unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        assert!(store.versions.is_empty());
+
+        // a key written again does not go back to the version it had
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set("c", "3");
+        assert!(matches!(
+            store.commit_transaction(vec![(b"a".to_vec(), version)], batch),
+            Err(KvsError::Conflict)
+        ));
     }
 }
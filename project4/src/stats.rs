@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+// the state of an engine as of the moment it was asked,
+// the counts an engine does not keep are left at zero
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    // the keys which are set and not expired
+    pub live_keys: u64,
+    // the bytes of data the engine keeps on disk
+    pub disk_bytes: u64,
+    // the part of disk_bytes superseded by later writes, which compaction would reclaim
+    pub stale_bytes: u64,
+    pub segments: u64,
+    // the compactions run since the engine was opened
+    pub compactions: u64,
+    // how long the last of them took in milliseconds, None before the first
+    pub last_compaction_ms: Option<u64>,
+    // an estimate of the memory taken by the in-memory index
+    pub index_bytes: u64,
+    // how many times smaller the records written since the engine was opened got
+    // through compression, 1 when nothing was written
+    pub compression_ratio: f64,
+}
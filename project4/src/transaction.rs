@@ -0,0 +1,127 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{binary, KvsEngine, KvsError, Result, WriteBatch};
+
+// an optimistic transaction over an engine, begun by KvsEngine::begin:
+// reads go to the engine and remember the version of every key they saw,
+// writes are buffered until commit, which applies them all at once
+// unless a key read has changed since, failing with KvsError::Conflict then
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    read_versions: HashMap<Vec<u8>, u64>,
+    // None stands for a removal
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Self {
+            engine,
+            read_versions: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // a transaction reads its own writes
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.engine.get_bytes_with_version(key.clone())?;
+        match self.read_versions.entry(key) {
+            // the commit is bound to fail once a key reads differently
+            Entry::Occupied(entry) if *entry.get() != version => Err(KvsError::Conflict),
+            Entry::Occupied(_) => Ok(value),
+            Entry::Vacant(entry) => {
+                entry.insert(version);
+                Ok(value)
+            }
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    // removing a key which does not exist is not an error, as within a batch
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        binary::into_string_opt(self.get_bytes(key.into_bytes())?)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.engine
+            .commit_transaction(self.read_versions.into_iter().collect(), batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{KvStore, KvsEngine, KvsError, SledKvsEngine};
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn check_transactions(store: impl KvsEngine) {
+        store.set("a".to_owned(), "1".to_owned()).unwrap();
+        store.set("c".to_owned(), "3".to_owned()).unwrap();
+
+        let mut txn = store.begin();
+        assert_eq!(txn.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        txn.set("b".to_owned(), "2".to_owned());
+        txn.remove("c".to_owned());
+        // a transaction reads its own writes, which nobody else sees before the commit
+        assert_eq!(txn.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(store.get("b".to_owned()).unwrap(), None);
+        txn.commit().unwrap();
+        assert_eq!(store.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+        assert_eq!(store.get("c".to_owned()).unwrap(), None);
+
+        // a key read, or found absent, and written by another handle since
+        for key in ["a", "d"] {
+            let mut txn = store.begin();
+            txn.get(key.to_owned()).unwrap();
+            txn.set("e".to_owned(), "5".to_owned());
+            let other = store.clone();
+            thread::spawn(move || other.set(key.to_owned(), "4".to_owned()).unwrap())
+                .join()
+                .unwrap();
+            assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+            assert_eq!(store.get("e".to_owned()).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_kvstore_transactions() {
+        check_transactions(KvStore::open(store_dir("txn")).unwrap());
+    }
+
+    #[test]
+    fn test_sled_transactions() {
+        check_transactions(SledKvsEngine::open(store_dir("sled-txn")).unwrap());
+    }
+}
@@ -1,35 +1,177 @@
-// use ron::{from_str, to_string};
+// every message on the wire is one bson document,
+// which starts with its own length and carries binary as is
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
 use serde::{de::DeserializeOwned, Serialize};
-use serde_json::{from_str, to_string};
 
 use crate::{KvsError, Result};
 
+// files are sent in chunks of at most this many bytes, well below the bson document limit
+const FILE_CHUNK_SIZE: usize = 1 << 20;
+// the largest message read, which is the bson document limit,
+// so that a peer cannot make the other side allocate whatever it claims to send
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
 pub fn to_bytes<T: Serialize>(comm: T) -> Result<Vec<u8>> {
-    to_string(&comm)
-        .map(|s| s.into_bytes())
-        .map_err(KvsError::KSPSerdeError)
+    Ok(bson::to_vec(&comm)?)
 }
 
 pub fn from_bytes<T: DeserializeOwned>(bytes: Vec<u8>) -> Result<T> {
-    from_str(std::str::from_utf8(&bytes).expect("all strings in this kvstore shall be safe"))
-        .map_err(KvsError::KSPSerdeError)
+    Ok(bson::from_slice(&bytes)?)
+}
+
+// read the next message, None once the peer has closed the connection
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = i32::from_le_bytes(len);
+    if len < 5 {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "invalid message length").into());
+    }
+    if len as usize > MAX_MESSAGE_SIZE {
+        let e = format!(
+            "message of {} bytes over the limit of {}",
+            len, MAX_MESSAGE_SIZE
+        );
+        return Err(std::io::Error::new(ErrorKind::InvalidData, e).into());
+    }
+    let mut bytes = vec![0; len as usize];
+    bytes[..4].copy_from_slice(&len.to_le_bytes());
+    reader.read_exact(&mut bytes[4..])?;
+    from_bytes(bytes).map(Some)
+}
+
+// send every file of dir in chunks of (name, bytes), an empty file as a single empty chunk.
+// files go in name order, which puts a manifest after the logs it names
+pub fn send_files(dir: &Path, mut send: impl FnMut(String, Vec<u8>) -> Result<()>) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort_unstable();
+    for name in names {
+        let mut file = File::open(dir.join(&name))?;
+        let mut first = true;
+        loop {
+            let mut chunk = Vec::with_capacity(FILE_CHUNK_SIZE);
+            (&mut file)
+                .take(FILE_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() && !first {
+                break;
+            }
+            first = false;
+            send(name.clone(), chunk)?;
+        }
+    }
+    Ok(())
+}
+
+// writes the chunks sent by send_files into a dir,
+// the chunks of one file coming one after the other
+pub struct FileReceiver {
+    dir: PathBuf,
+    current: Option<(String, File)>,
+}
+
+impl FileReceiver {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            current: None,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn receive(&mut self, name: String, chunk: &[u8]) -> Result<()> {
+        // the peer names files within the dir and nowhere else
+        let mut components = Path::new(&name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(KvsError::InvalidFileName(name));
+        }
+        match self.current.as_mut() {
+            Some((current, file)) if *current == name => file.write_all(chunk)?,
+            _ => {
+                self.finish()?;
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(self.dir.join(&name))?;
+                file.write_all(chunk)?;
+                self.current = Some((name, file));
+            }
+        }
+        Ok(())
+    }
+
+    // make the file written last durable
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some((_, file)) = self.current.take() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{from_str, to_string};
-    use crate::KSP;
+    use super::{read_message, to_bytes};
+    use crate::{Response, WriteBatch, KSP};
+    use std::ops::Bound;
 
     #[test]
     fn test_serde() {
+        let mut batch = WriteBatch::new();
+        batch.set("a", vec![0, 0xff]).remove("c");
         let cases = [
-            KSP::Set("a".to_owned(), "b".to_owned()),
-            KSP::Get("a".to_owned()),
-            KSP::Rm("a".to_owned()),
+            KSP::Set(b"a".to_vec(), b"b".to_vec()),
+            KSP::Set(vec![0xff, 0], vec![0, 159, 146, 150]),
+            KSP::Get(b"a".to_vec()),
+            KSP::Rm(b"a".to_vec()),
+            KSP::Cas(b"a".to_vec(), None, Some(vec![0xc3])),
+            KSP::Batch(batch),
+            KSP::Scan(Bound::Excluded(vec![0xfe]), Bound::Unbounded, 10),
         ];
+        let bytes: Vec<u8> = cases
+            .iter()
+            .flat_map(|case| to_bytes(case).unwrap())
+            .collect();
+        let mut reader = bytes.as_slice();
         for case in cases {
-            let bytes: String = to_string(&case).expect("fuck");
-            assert_eq!(from_str::<KSP>(&bytes).unwrap(), case);
+            assert_eq!(read_message::<KSP>(&mut reader).unwrap(), Some(case));
+        }
+        assert_eq!(read_message::<KSP>(&mut reader).unwrap(), None);
+
+        let resp = Response::Scan(vec![(vec![1], vec![0xff])], Some(vec![1]));
+        let bytes = to_bytes(&resp).unwrap();
+        match read_message(&mut bytes.as_slice()).unwrap() {
+            Some(Response::Scan(entries, next)) => {
+                assert_eq!(entries, vec![(vec![1], vec![0xff])]);
+                assert_eq!(next, Some(vec![1]));
+            }
+            resp => panic!("unexpected resp {:?}", resp),
         }
     }
+
+    #[test]
+    fn test_oversized_message() {
+        let mut bytes = i32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+        assert!(read_message::<KSP>(&mut bytes.as_slice()).is_err());
+    }
 }
@@ -0,0 +1,17 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// how often the engines look for keys whose ttl ran out
+pub(crate) const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// expiry times are kept as milliseconds since the unix epoch,
+// so that they stay meaningful across restarts
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the clock is set before the unix epoch")
+        .as_millis() as u64
+}
+
+pub(crate) fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}